@@ -0,0 +1,224 @@
+//! Compares the striped adapter scan against the `Vec<Vec<i32>>` Gotoh
+//! alignment over `Vec<char>` it replaced, and against the flat full-matrix
+//! alignment it is checked with, making sure all report the same hit.
+//!
+//! cargo bench --bench adapter_scan
+
+use std::time::{Duration, Instant};
+
+#[allow(dead_code)]
+#[path = "../src/align.rs"]
+mod align;
+
+use align::{full_matrix_align, smith_waterman_align, Alignment, Scoring};
+
+const ADAPTER: &str = "AATTTAAGTGAAATGCTAAAATCAAAGGTTATGAA";
+
+/// Small xorshift generator so the bench needs no extra dependencies.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn base(&mut self) -> u8 {
+        b"ACGT"[(self.next() % 4) as usize]
+    }
+}
+
+/// Random read with a mutated copy of the adapter in the middle, and a
+/// matching Phred+33 quality string.
+fn make_read(rng: &mut Rng, len: usize) -> (String, String) {
+    let mut read: Vec<u8> = (0..len).map(|_| rng.base()).collect();
+    let mut adapter = Vec::new();
+    for &b in ADAPTER.as_bytes() {
+        match rng.next() % 20 {
+            0 => {}
+            1 => adapter.push(rng.base()),
+            2 => {
+                adapter.push(b);
+                adapter.push(rng.base());
+            }
+            _ => adapter.push(b),
+        }
+    }
+    let pos = len / 2;
+    read.splice(pos..pos, adapter);
+    let quality: String = read.iter().map(|_| (b'!' + (rng.next() % 40) as u8) as char).collect();
+    (String::from_utf8(read).unwrap(), quality)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Diagonal,
+    ReadGap,
+    AdapterGap,
+}
+
+/// The adapter search before the striped scan: full `Vec<Vec<i32>>`
+/// matrices over `Vec<char>`, without quality-aware mismatches or IUPAC codes.
+fn baseline_align(sequence: &str, adapter: &str, scoring: &Scoring) -> Option<Alignment> {
+    let seq_chars: Vec<char> = sequence.chars().collect();
+    let adapter_chars: Vec<char> = adapter.chars().collect();
+    let m = seq_chars.len();
+    let n = adapter_chars.len();
+
+    if m == 0 || n == 0 {
+        return None;
+    }
+
+    let neg_inf = i32::MIN / 2;
+    let mut matrix = vec![vec![0; n + 1]; m + 1];
+    let mut read_gap = vec![vec![neg_inf; n + 1]; m + 1];
+    let mut adapter_gap = vec![vec![neg_inf; n + 1]; m + 1];
+    let mut max_score = 0;
+    let mut max_i = 0;
+    let mut max_j = 0;
+
+    let substitution = |i: usize, j: usize| {
+        if seq_chars[i - 1] == adapter_chars[j - 1] {
+            scoring.match_score
+        } else {
+            -scoring.mismatch_penalty
+        }
+    };
+
+    for i in 1..=m {
+        for j in 1..=n {
+            read_gap[i][j] = (matrix[i - 1][j] - scoring.gap_open)
+                .max(read_gap[i - 1][j] - scoring.gap_extend);
+            adapter_gap[i][j] = (matrix[i][j - 1] - scoring.gap_open)
+                .max(adapter_gap[i][j - 1] - scoring.gap_extend);
+
+            let diagonal = matrix[i - 1][j - 1] + substitution(i, j);
+            matrix[i][j] = 0.max(diagonal).max(read_gap[i][j]).max(adapter_gap[i][j]);
+
+            if matrix[i][j] > max_score {
+                max_score = matrix[i][j];
+                max_i = i;
+                max_j = j;
+            }
+        }
+    }
+
+    if max_score <= 0 {
+        return None;
+    }
+
+    let mut i = max_i;
+    let mut j = max_j;
+    let mut matches = 0;
+    let mut mismatches = 0;
+    let mut indels = 0;
+    let mut state = State::Diagonal;
+
+    while i > 0 && j > 0 {
+        match state {
+            State::Diagonal => {
+                if matrix[i][j] <= 0 {
+                    break;
+                }
+                if matrix[i][j] == matrix[i - 1][j - 1] + substitution(i, j) {
+                    if seq_chars[i - 1] == adapter_chars[j - 1] {
+                        matches += 1;
+                    } else {
+                        mismatches += 1;
+                    }
+                    i -= 1;
+                    j -= 1;
+                } else if matrix[i][j] == read_gap[i][j] {
+                    state = State::ReadGap;
+                } else if matrix[i][j] == adapter_gap[i][j] {
+                    state = State::AdapterGap;
+                } else {
+                    break;
+                }
+            }
+            State::ReadGap => {
+                indels += 1;
+                let opened = read_gap[i][j] == matrix[i - 1][j] - scoring.gap_open;
+                i -= 1;
+                if opened {
+                    state = State::Diagonal;
+                }
+            }
+            State::AdapterGap => {
+                indels += 1;
+                let opened = adapter_gap[i][j] == matrix[i][j - 1] - scoring.gap_open;
+                j -= 1;
+                if opened {
+                    state = State::Diagonal;
+                }
+            }
+        }
+    }
+
+    Some(Alignment {
+        read_start: i,
+        read_end: max_i,
+        adapter_start: j,
+        adapter_end: max_j,
+        score: max_score,
+        matches,
+        mismatches,
+        indels,
+    })
+}
+
+fn time<F: FnMut()>(mut f: F) -> Duration {
+    let start = Instant::now();
+    f();
+    start.elapsed()
+}
+
+fn main() {
+    let mut rng = Rng(0x9e3779b97f4a7c15);
+    let scorings = [
+        Scoring::default(),
+        Scoring { match_score: 2, mismatch_penalty: 2, gap_open: 3, gap_extend: 1, ..Scoring::default() },
+        Scoring { mismatch_penalty: 3, mismatch_quality_cap: Some(20), ..Scoring::default() },
+    ];
+
+    println!("{:>8} {:>6} {:>14} {:>14} {:>14} {:>8}", "read_len", "reads", "baseline", "full_matrix", "striped", "speedup");
+    for &(len, count) in &[(1_000, 200), (10_000, 50), (100_000, 5)] {
+        let reads: Vec<(String, String)> = (0..count).map(|_| make_read(&mut rng, len)).collect();
+        for scoring in &scorings {
+            let mut baseline_hits = Vec::new();
+            let baseline = time(|| {
+                for (read, _) in &reads {
+                    baseline_hits.push(baseline_align(read, ADAPTER, scoring));
+                }
+            });
+            let mut full_hits = Vec::new();
+            let full = time(|| {
+                for (read, quality) in &reads {
+                    full_hits.push(full_matrix_align(read.as_bytes(), quality.as_bytes(), ADAPTER.as_bytes(), scoring));
+                }
+            });
+            let mut striped_hits = Vec::new();
+            let striped = time(|| {
+                for (read, quality) in &reads {
+                    striped_hits.push(smith_waterman_align(read, quality, ADAPTER, scoring));
+                }
+            });
+            assert_eq!(full_hits, striped_hits, "striped scan disagrees with full matrix");
+            // The baseline has no quality-aware mismatches to agree on.
+            if scoring.mismatch_quality_cap.is_none() {
+                assert_eq!(baseline_hits, striped_hits, "striped scan disagrees with the baseline");
+            }
+            println!(
+                "{:>8} {:>6} {:>12.1}ms {:>12.1}ms {:>12.1}ms {:>7.1}x",
+                len,
+                count,
+                baseline.as_secs_f64() * 1e3,
+                full.as_secs_f64() * 1e3,
+                striped.as_secs_f64() * 1e3,
+                baseline.as_secs_f64() / striped.as_secs_f64()
+            );
+        }
+    }
+}
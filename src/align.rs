@@ -0,0 +1,879 @@
+/// Scoring scheme for adapter alignment.
+///
+/// Penalties are positive costs. A gap of length `k` costs
+/// `gap_open + (k - 1) * gap_extend`, so `gap_open == gap_extend` gives
+/// the old linear gap model.
+#[derive(Clone, Copy, Debug)]
+pub struct Scoring {
+    pub match_score: i32,
+    pub mismatch_penalty: i32,
+    pub gap_open: i32,
+    pub gap_extend: i32,
+    /// When set, a mismatch against a read base of Phred quality `q` costs
+    /// `mismatch_penalty * min(q, cap) / cap`, rounded.
+    pub mismatch_quality_cap: Option<u8>,
+    /// Let IUPAC codes in the read (`N` and the like) match adapter bases,
+    /// not just those in the adapter.
+    pub match_read_wildcards: bool,
+}
+
+impl Scoring {
+    /// Mismatch penalty against a read base with Phred+33 quality `qual`.
+    fn mismatch_for(&self, qual: Option<&u8>) -> i32 {
+        match (self.mismatch_quality_cap, qual) {
+            (Some(cap), Some(&qual)) if cap > 0 => {
+                let cap = cap as i32;
+                let phred = (qual.saturating_sub(33) as i32).min(cap);
+                (self.mismatch_penalty * phred + cap / 2) / cap
+            }
+            _ => self.mismatch_penalty,
+        }
+    }
+}
+
+impl Default for Scoring {
+    fn default() -> Self {
+        Scoring {
+            match_score: 2,
+            mismatch_penalty: 1,
+            gap_open: 2,
+            gap_extend: 2,
+            mismatch_quality_cap: None,
+            match_read_wildcards: false,
+        }
+    }
+}
+
+/// A local alignment of (part of) an adapter against a read.
+///
+/// Coordinates are half-open: the hit covers `sequence[read_start..read_end]`
+/// and `adapter[adapter_start..adapter_end]`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Alignment {
+    pub read_start: usize,
+    pub read_end: usize,
+    pub adapter_start: usize,
+    pub adapter_end: usize,
+    pub score: i32,
+    pub matches: usize,
+    pub mismatches: usize,
+    pub indels: usize,
+}
+
+impl Alignment {
+    /// Number of adapter bases covered by the alignment.
+    pub fn aligned_length(&self) -> usize {
+        self.adapter_end - self.adapter_start
+    }
+
+    /// Fraction of alignment columns that are matches.
+    pub fn identity(&self) -> f64 {
+        let columns = self.matches + self.mismatches + self.indels;
+        if columns == 0 {
+            0.0
+        } else {
+            self.matches as f64 / columns as f64
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Diagonal,
+    ReadGap,
+    AdapterGap,
+}
+
+const LANES: usize = 8;
+const NEG_INF: i32 = i32::MIN / 2;
+const LANE_NEG_INF: i16 = i16::MIN / 2;
+
+/// Eight 16-bit lanes; lane-wise max and saturating add map onto plain SSE2.
+type Lanes = [i16; LANES];
+
+#[inline(always)]
+fn splat(value: i16) -> Lanes {
+    [value; LANES]
+}
+
+#[inline(always)]
+fn add(a: Lanes, b: Lanes) -> Lanes {
+    let mut out = a;
+    for k in 0..LANES {
+        out[k] = out[k].saturating_add(b[k]);
+    }
+    out
+}
+
+#[inline(always)]
+fn sub(a: Lanes, value: i16) -> Lanes {
+    let mut out = a;
+    for lane in out.iter_mut() {
+        *lane = lane.saturating_sub(value);
+    }
+    out
+}
+
+#[inline(always)]
+fn max(a: Lanes, b: Lanes) -> Lanes {
+    let mut out = a;
+    for k in 0..LANES {
+        out[k] = out[k].max(b[k]);
+    }
+    out
+}
+
+#[inline(always)]
+fn any_gt(a: Lanes, b: Lanes) -> bool {
+    (0..LANES).any(|k| a[k] > b[k])
+}
+
+/// Moves every lane up by one, filling lane 0 with `fill`.
+#[inline(always)]
+fn shift_in(v: Lanes, fill: i16) -> Lanes {
+    let mut out = [fill; LANES];
+    out[1..].copy_from_slice(&v[..LANES - 1]);
+    out
+}
+
+/// IUPAC code as a bit set over A, C, G, T; case-insensitive. Anything
+/// that is not a nucleotide code maps to the empty set.
+fn iupac_mask(base: u8) -> u8 {
+    const A: u8 = 1;
+    const C: u8 = 2;
+    const G: u8 = 4;
+    const T: u8 = 8;
+    match base.to_ascii_uppercase() {
+        b'A' => A,
+        b'C' => C,
+        b'G' => G,
+        // RNA uracil pairs like thymine.
+        b'T' | b'U' => T,
+        b'R' => A | G,
+        b'Y' => C | T,
+        b'S' => C | G,
+        b'W' => A | T,
+        b'K' => G | T,
+        b'M' => A | C,
+        b'B' => C | G | T,
+        b'D' => A | G | T,
+        b'H' => A | C | T,
+        b'V' => A | C | G,
+        b'N' => A | C | G | T,
+        _ => 0,
+    }
+}
+
+/// A read base matches an adapter base when the nucleotides they stand for
+/// overlap. Codes such as `N` or `R` are wildcards in the adapter; in the
+/// read they are mismatches unless `read_wildcards` is set, so runs of
+/// uncalled bases do not pass for an adapter.
+pub fn bases_match(read_base: u8, adapter_base: u8, read_wildcards: bool) -> bool {
+    let read = iupac_mask(read_base);
+    let read = if read_wildcards || read.is_power_of_two() { read } else { 0 };
+    read & iupac_mask(adapter_base) != 0
+}
+
+fn substitution(read_base: u8, adapter_base: u8, mismatch_penalty: i32, scoring: &Scoring) -> i32 {
+    if bases_match(read_base, adapter_base, scoring.match_read_wildcards) {
+        scoring.match_score
+    } else {
+        -mismatch_penalty
+    }
+}
+
+/// One striped row, ignoring adapter gaps that cross a lane boundary.
+///
+/// Returns the lane-wise row maximum and the adapter gap leaving the last
+/// segment.
+fn striped_row(
+    profile: &[Lanes],
+    h_load: &[Lanes],
+    h_store: &mut [Lanes],
+    read_gap: &mut [Lanes],
+    adapter_gap: &mut [Lanes],
+    gap_open: i16,
+    gap_extend: i16,
+) -> (Lanes, Lanes) {
+    let mut row_max = splat(0);
+    let mut v_gap = splat(LANE_NEG_INF);
+    let mut v_h = shift_in(h_load[h_load.len() - 1], 0);
+    let cells = h_store
+        .iter_mut()
+        .zip(read_gap.iter_mut())
+        .zip(adapter_gap.iter_mut())
+        .zip(profile.iter().zip(h_load.iter()));
+    for (((h_out, e), f), (p, h_prev)) in cells {
+        *f = v_gap;
+        v_h = max(max(add(v_h, *p), *e), max(v_gap, splat(0)));
+        *h_out = v_h;
+        row_max = max(row_max, v_h);
+
+        let v_open = sub(v_h, gap_open);
+        *e = max(sub(*e, gap_extend), v_open);
+        v_gap = max(sub(v_gap, gap_extend), v_open);
+        v_h = *h_prev;
+    }
+    (row_max, v_gap)
+}
+
+/// Score-only striped (Farrar) pass over the read.
+///
+/// Adapter position `j` lives in lane `j / seg_len` of segment `j % seg_len`,
+/// so one read base updates all adapter positions with `seg_len` lane-wise
+/// operations. Returns the best score and the first read row (1-based) that
+/// reaches it, which is the row the full-matrix scan would pick, or `None`
+/// when the scores could overflow 16-bit lanes.
+///
+/// Profiles are keyed by read base and mismatch penalty, so quality-scaled
+/// penalties only add one profile per distinct penalty level.
+fn striped_best_row(seq: &[u8], qual: &[u8], adapter: &[u8], scoring: &Scoring) -> Option<(i32, usize)> {
+    let n = adapter.len();
+    let lane_limit = (i16::MAX / 4) as i64;
+    if scoring.match_score as i64 * n as i64 > lane_limit
+        || scoring.mismatch_penalty as i64 > lane_limit
+        || scoring.gap_open as i64 > lane_limit
+        || scoring.gap_extend as i64 > lane_limit
+        || (scoring.mismatch_quality_cap.is_some() && scoring.mismatch_penalty > u8::MAX as i32)
+    {
+        return None;
+    }
+    let levels = if scoring.mismatch_quality_cap.is_some() {
+        scoring.mismatch_penalty as usize + 1
+    } else {
+        1
+    };
+    let gap_open = scoring.gap_open as i16;
+    let gap_extend = scoring.gap_extend as i16;
+    let seg_len = n.div_ceil(LANES);
+
+    let mut profile_of = vec![usize::MAX; 256 * levels];
+    let mut profiles: Vec<Vec<Lanes>> = Vec::new();
+    let mut h_load = vec![splat(0); seg_len];
+    let mut h_store = vec![splat(0); seg_len];
+    let mut read_gap = vec![splat(LANE_NEG_INF); seg_len];
+    let mut adapter_gap = vec![splat(LANE_NEG_INF); seg_len];
+
+    let mut best = 0;
+    let mut best_row = 0;
+
+    for (row, &base) in seq.iter().enumerate() {
+        let mismatch_penalty = scoring.mismatch_for(qual.get(row));
+        let key = base as usize * levels + (mismatch_penalty as usize).min(levels - 1);
+        if profile_of[key] == usize::MAX {
+            let profile = (0..seg_len)
+                .map(|s| {
+                    let mut lanes = splat(LANE_NEG_INF);
+                    for (k, lane) in lanes.iter_mut().enumerate() {
+                        let j = k * seg_len + s;
+                        if j < n {
+                            *lane = substitution(base, adapter[j], mismatch_penalty, scoring) as i16;
+                        }
+                    }
+                    lanes
+                })
+                .collect();
+            profile_of[key] = profiles.len();
+            profiles.push(profile);
+        }
+        let profile = &profiles[profile_of[key]];
+
+        let (mut row_max, v_gap) = striped_row(
+            profile,
+            &h_load,
+            &mut h_store,
+            &mut read_gap,
+            &mut adapter_gap,
+            gap_open,
+            gap_extend,
+        );
+
+        // Adapter gaps that cross a lane boundary were missed above; carry
+        // them around until they stop improving any cell. Gap scores at or
+        // below zero can never lift a cell above the zero floor.
+        let mut v_gap = shift_in(v_gap, LANE_NEG_INF);
+        let mut s = 0;
+        while any_gt(v_gap, max(adapter_gap[s], splat(0))) {
+            let gap_in = max(v_gap, adapter_gap[s]);
+            adapter_gap[s] = gap_in;
+            let v_h = max(h_store[s], gap_in);
+            h_store[s] = v_h;
+            row_max = max(row_max, v_h);
+
+            let v_open = sub(v_h, gap_open);
+            read_gap[s] = max(read_gap[s], v_open);
+            v_gap = max(sub(gap_in, gap_extend), v_open);
+            s += 1;
+            if s == seg_len {
+                s = 0;
+                v_gap = shift_in(v_gap, LANE_NEG_INF);
+            }
+        }
+
+        let row_best = row_max.iter().copied().max().unwrap_or(0) as i32;
+        if row_best > best {
+            best = row_best;
+            best_row = row + 1;
+        }
+        std::mem::swap(&mut h_load, &mut h_store);
+    }
+
+    Some((best, best_row))
+}
+
+/// Number of read bases before the best cell that the traceback may need.
+///
+/// The optimal path gains at most `match_score * n` and ends at `best`, so
+/// it pays at most the difference in gaps, which caps its read-consuming
+/// gap steps. Cells on the path then score the same inside the window as in
+/// the full matrix, and cells off the path can only score lower, so the
+/// traceback takes the same steps.
+fn traceback_window(adapter_len: usize, best: i32, scoring: &Scoring) -> usize {
+    let min_gap = scoring.gap_open.min(scoring.gap_extend);
+    if min_gap <= 0 {
+        return usize::MAX;
+    }
+    let slack = (scoring.match_score as usize * adapter_len).saturating_sub(best as usize);
+    adapter_len + slack / min_gap as usize + 2
+}
+
+/// Local alignment with affine gaps (Gotoh).
+///
+/// The read is scanned with a striped score-only pass, then the full matrix
+/// is filled only for a window ending at the best cell, so long reads never
+/// allocate a read-length matrix. Hits are identical to
+/// [`full_matrix_align`] over the whole read.
+///
+/// `quality` is only consulted when the scoring scales mismatches by
+/// quality; it may be empty otherwise.
+pub fn smith_waterman_align(sequence: &str, quality: &str, adapter: &str, scoring: &Scoring) -> Option<Alignment> {
+    let seq = sequence.as_bytes();
+    let qual = quality.as_bytes();
+    let adapter = adapter.as_bytes();
+    if seq.is_empty() || adapter.is_empty() {
+        return None;
+    }
+
+    let Some((best, end)) = striped_best_row(seq, qual, adapter, scoring) else {
+        return full_matrix_align(seq, qual, adapter, scoring);
+    };
+    if best <= 0 {
+        return None;
+    }
+
+    let start = end.saturating_sub(traceback_window(adapter.len(), best, scoring));
+    let window_qual = qual.get(start..end).unwrap_or(&[]);
+    let mut hit = full_matrix_align(&seq[start..end], window_qual, adapter, scoring)?;
+    hit.read_start += start;
+    hit.read_end += start;
+    Some(hit)
+}
+
+/// Local alignment with affine gaps (Gotoh) over the full DP matrix.
+///
+/// `read_gap` holds alignments ending in a gap that consumes read bases,
+/// `adapter_gap` those ending in a gap that consumes adapter bases.
+pub fn full_matrix_align(seq: &[u8], qual: &[u8], adapter: &[u8], scoring: &Scoring) -> Option<Alignment> {
+    let m = seq.len();
+    let n = adapter.len();
+
+    if m == 0 || n == 0 {
+        return None;
+    }
+
+    let width = n + 1;
+    let idx = |i: usize, j: usize| i * width + j;
+    let mut matrix = vec![0; (m + 1) * width];
+    let mut read_gap = vec![NEG_INF; (m + 1) * width];
+    let mut adapter_gap = vec![NEG_INF; (m + 1) * width];
+    let mut max_score = 0;
+    let mut max_i = 0;
+    let mut max_j = 0;
+
+    let mismatch: Vec<i32> = (0..m).map(|i| scoring.mismatch_for(qual.get(i))).collect();
+
+    for i in 1..=m {
+        for j in 1..=n {
+            read_gap[idx(i, j)] = (matrix[idx(i - 1, j)] - scoring.gap_open)
+                .max(read_gap[idx(i - 1, j)] - scoring.gap_extend);
+            adapter_gap[idx(i, j)] = (matrix[idx(i, j - 1)] - scoring.gap_open)
+                .max(adapter_gap[idx(i, j - 1)] - scoring.gap_extend);
+
+            let diagonal = matrix[idx(i - 1, j - 1)] + substitution(seq[i - 1], adapter[j - 1], mismatch[i - 1], scoring);
+            let score = 0.max(diagonal).max(read_gap[idx(i, j)]).max(adapter_gap[idx(i, j)]);
+            matrix[idx(i, j)] = score;
+
+            if score > max_score {
+                max_score = score;
+                max_i = i;
+                max_j = j;
+            }
+        }
+    }
+
+    if max_score <= 0 {
+        return None;
+    }
+
+    let mut i = max_i;
+    let mut j = max_j;
+    let mut matches = 0;
+    let mut mismatches = 0;
+    let mut indels = 0;
+    let mut state = State::Diagonal;
+
+    while i > 0 && j > 0 {
+        match state {
+            State::Diagonal => {
+                let score = matrix[idx(i, j)];
+                if score <= 0 {
+                    break;
+                }
+                if score == matrix[idx(i - 1, j - 1)] + substitution(seq[i - 1], adapter[j - 1], mismatch[i - 1], scoring) {
+                    if bases_match(seq[i - 1], adapter[j - 1], scoring.match_read_wildcards) {
+                        matches += 1;
+                    } else {
+                        mismatches += 1;
+                    }
+                    i -= 1;
+                    j -= 1;
+                } else if score == read_gap[idx(i, j)] {
+                    state = State::ReadGap;
+                } else if score == adapter_gap[idx(i, j)] {
+                    state = State::AdapterGap;
+                } else {
+                    break;
+                }
+            }
+            State::ReadGap => {
+                indels += 1;
+                let opened = read_gap[idx(i, j)] == matrix[idx(i - 1, j)] - scoring.gap_open;
+                i -= 1;
+                if opened {
+                    state = State::Diagonal;
+                }
+            }
+            State::AdapterGap => {
+                indels += 1;
+                let opened = adapter_gap[idx(i, j)] == matrix[idx(i, j - 1)] - scoring.gap_open;
+                j -= 1;
+                if opened {
+                    state = State::Diagonal;
+                }
+            }
+        }
+    }
+
+    Some(Alignment {
+        read_start: i,
+        read_end: max_i,
+        adapter_start: j,
+        adapter_end: max_j,
+        score: max_score,
+        matches,
+        mismatches,
+        indels,
+    })
+}
+
+/// An adapter fragment hanging off one end of the read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Overlap {
+    /// Read bases covered, counted from the anchored read end.
+    pub read_len: usize,
+    /// Adapter bases covered, counted from the anchored adapter end.
+    pub adapter_len: usize,
+    pub matches: usize,
+    pub errors: usize,
+}
+
+/// Read bases an anchored overlap can cover: the whole adapter plus one
+/// inserted base per allowed error. [`anchored_overlap`] never looks past
+/// them, so callers only need to pass this much of the read.
+pub fn overlap_span(adapter_len: usize, max_error_rate: f64) -> usize {
+    adapter_len + (max_error_rate * adapter_len as f64).floor() as usize
+}
+
+/// Semi-global alignment of a suffix of `adapter` against a prefix of `read`.
+///
+/// This is the shape of an adapter cut off by the 5' end of the read; the
+/// 3' case is the same problem on both sequences reversed. Among overlaps of
+/// at least `min_overlap` adapter bases with at most `max_error_rate` edits
+/// per adapter base, the one with the most matches (then fewest errors) wins.
+/// `read_wildcards` is as for [`bases_match`].
+pub fn anchored_overlap(
+    read: &[u8],
+    adapter: &[u8],
+    min_overlap: usize,
+    max_error_rate: f64,
+    read_wildcards: bool,
+) -> Option<Overlap> {
+    let n = adapter.len();
+    let m = read.len().min(overlap_span(n, max_error_rate));
+    if m == 0 || n == 0 {
+        return None;
+    }
+
+    // (errors, matches, adapter start) per cell; fewer errors win, then more matches.
+    #[derive(Clone, Copy)]
+    struct Cell {
+        errors: usize,
+        matches: usize,
+        origin: usize,
+    }
+    let better = |a: Cell, b: Cell| {
+        if a.errors != b.errors {
+            a.errors < b.errors
+        } else {
+            a.matches > b.matches
+        }
+    };
+
+    let width = n + 1;
+    let mut cells = Vec::with_capacity((m + 1) * width);
+    // Any adapter suffix may start the overlap for free, but every read
+    // base before it is an error.
+    cells.extend((0..=n).map(|j| Cell { errors: 0, matches: 0, origin: j }));
+    for i in 1..=m {
+        cells.push(Cell { errors: i, matches: 0, origin: 0 });
+        for j in 1..=n {
+            let diag = cells[(i - 1) * width + j - 1];
+            let same = bases_match(read[i - 1], adapter[j - 1], read_wildcards);
+            let mut best = Cell {
+                errors: diag.errors + usize::from(!same),
+                matches: diag.matches + usize::from(same),
+                origin: diag.origin,
+            };
+            let up = cells[(i - 1) * width + j];
+            let up = Cell { errors: up.errors + 1, ..up };
+            if better(up, best) {
+                best = up;
+            }
+            let left = cells[i * width + j - 1];
+            let left = Cell { errors: left.errors + 1, ..left };
+            if better(left, best) {
+                best = left;
+            }
+            cells.push(best);
+        }
+    }
+
+    let mut found: Option<Overlap> = None;
+    for i in 1..=m {
+        let cell = cells[i * width + n];
+        let adapter_len = n - cell.origin;
+        if adapter_len < min_overlap || cell.errors as f64 > max_error_rate * adapter_len as f64 {
+            continue;
+        }
+        let candidate = Overlap {
+            read_len: i,
+            adapter_len,
+            matches: cell.matches,
+            errors: cell.errors,
+        };
+        let replace = match found {
+            None => true,
+            Some(prev) => {
+                candidate.matches > prev.matches
+                    || (candidate.matches == prev.matches && candidate.errors < prev.errors)
+            }
+        };
+        if replace {
+            found = Some(candidate);
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADAPTER: &str = "ACGTTGCAAGTC";
+
+    fn affine() -> Scoring {
+        Scoring { gap_open: 4, gap_extend: 1, ..Scoring::default() }
+    }
+
+    #[test]
+    fn exact_hit_coordinates() {
+        let read = format!("TTTTT{}GGGGG", ADAPTER);
+        let hit = smith_waterman_align(&read, "", ADAPTER, &Scoring::default()).unwrap();
+        assert_eq!((hit.read_start, hit.read_end), (5, 17));
+        assert_eq!((hit.adapter_start, hit.adapter_end), (0, 12));
+        assert_eq!((hit.score, hit.matches, hit.mismatches, hit.indels), (24, 12, 0, 0));
+    }
+
+    #[test]
+    fn mismatch_is_counted() {
+        let read = "TTTTTACGTTGGAAGTCGGGGG";
+        let hit = smith_waterman_align(read, "", ADAPTER, &Scoring::default()).unwrap();
+        assert_eq!((hit.score, hit.matches, hit.mismatches, hit.indels), (21, 11, 1, 0));
+        assert_eq!((hit.read_start, hit.read_end), (5, 17));
+    }
+
+    #[test]
+    fn read_insertion_pays_one_gap_open() {
+        // Two inserted read bases cost gap_open + gap_extend, not two opens.
+        let read = "TTTTTACGTTGAACAAGTCGGGGG";
+        let hit = smith_waterman_align(read, "", ADAPTER, &affine()).unwrap();
+        assert_eq!((hit.score, hit.matches, hit.mismatches, hit.indels), (24 - 5, 12, 0, 2));
+        assert_eq!((hit.read_start, hit.read_end), (5, 19));
+    }
+
+    #[test]
+    fn adapter_base_missing_from_read() {
+        let read = "TTTTTACGTTCAAGTCGGGGG";
+        let hit = smith_waterman_align(read, "", ADAPTER, &affine()).unwrap();
+        assert_eq!((hit.score, hit.matches, hit.mismatches, hit.indels), (22 - 4, 11, 0, 1));
+        assert_eq!((hit.adapter_start, hit.adapter_end), (0, 12));
+    }
+
+    #[test]
+    fn mismatch_penalty_scales_with_quality() {
+        let scoring = Scoring { mismatch_penalty: 4, mismatch_quality_cap: Some(40), ..Scoring::default() };
+        let phred = |q: u8| 33 + q;
+        assert_eq!(scoring.mismatch_for(Some(&phred(40))), 4);
+        assert_eq!(scoring.mismatch_for(Some(&phred(60))), 4);
+        assert_eq!(scoring.mismatch_for(Some(&phred(20))), 2);
+        assert_eq!(scoring.mismatch_for(Some(&phred(2))), 0);
+        assert_eq!(scoring.mismatch_for(None), 4);
+        let flat = Scoring { mismatch_penalty: 4, ..Scoring::default() };
+        assert_eq!(flat.mismatch_for(Some(&phred(2))), 4);
+
+        // The mismatched base costs less when it was called with low quality.
+        let read = "TTTTTACGTTGGAAGTCGGGGG";
+        let confident = "I".repeat(read.len());
+        let mut doubtful = confident.clone().into_bytes();
+        doubtful[11] = phred(2);
+        let doubtful = String::from_utf8(doubtful).unwrap();
+        let high = smith_waterman_align(read, &confident, ADAPTER, &scoring).unwrap();
+        let low = smith_waterman_align(read, &doubtful, ADAPTER, &scoring).unwrap();
+        assert_eq!((high.score, high.mismatches), (22 - 4, 1));
+        assert_eq!((low.score, low.mismatches), (22, 1));
+    }
+
+    /// xorshift64, so the random cases are the same on every run.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn bases(&mut self, len: usize) -> Vec<u8> {
+            (0..len).map(|_| b"ACGT"[self.below(4)]).collect()
+        }
+
+        /// `source` with about `rate` substitutions, insertions and
+        /// deletions per base.
+        fn mutate(&mut self, source: &[u8], rate: f64) -> Vec<u8> {
+            let mut out = Vec::new();
+            for &base in source {
+                let roll = self.below(1000) as f64 / 1000.0;
+                if roll < rate / 3.0 {
+                    continue;
+                } else if roll < 2.0 * rate / 3.0 {
+                    out.push(base);
+                    let inserted = 1 + self.below(3);
+                    out.extend(self.bases(inserted));
+                } else if roll < rate {
+                    out.push(b"ACGT"[self.below(4)]);
+                } else {
+                    out.push(base);
+                }
+            }
+            out
+        }
+    }
+
+    /// Best score and the first (row-major) cell reaching it, by brute force.
+    fn full_best(seq: &[u8], qual: &[u8], adapter: &[u8], scoring: &Scoring) -> (i32, usize) {
+        full_matrix_align(seq, qual, adapter, scoring).map_or((0, 0), |hit| (hit.score, hit.read_end))
+    }
+
+    fn check_striped(rng: &mut Rng, scoring: &Scoring, adapter_len: usize, rate: f64) {
+        let adapter = rng.bases(adapter_len);
+        let (before, after) = (rng.below(200), rng.below(200));
+        let mut read = rng.bases(before);
+        read.extend(rng.mutate(&adapter, rate));
+        read.extend(rng.bases(after));
+        let qual: Vec<u8> = (0..read.len()).map(|_| 33 + rng.below(40) as u8).collect();
+
+        let striped = striped_best_row(&read, &qual, &adapter, scoring).unwrap();
+        assert_eq!(striped, full_best(&read, &qual, &adapter, scoring), "adapter {:?}", adapter);
+
+        let sequence = String::from_utf8(read.clone()).unwrap();
+        let quality = String::from_utf8(qual.clone()).unwrap();
+        let adapter_str = String::from_utf8(adapter.clone()).unwrap();
+        let hit = smith_waterman_align(&sequence, &quality, &adapter_str, scoring);
+        assert_eq!(hit, full_matrix_align(&read, &qual, &adapter, scoring));
+        if let Some(hit) = hit {
+            assert!(hit.read_end - hit.read_start <= traceback_window(adapter.len(), hit.score, scoring));
+        }
+    }
+
+    #[test]
+    fn striped_matches_full_matrix_on_random_reads() {
+        let mut rng = Rng(0x9e3779b97f4a7c15);
+        for case in 0..200 {
+            let scoring = Scoring {
+                match_score: 1 + rng.below(3) as i32,
+                mismatch_penalty: rng.below(4) as i32,
+                gap_open: 1 + rng.below(4) as i32,
+                gap_extend: 1 + rng.below(3) as i32,
+                mismatch_quality_cap: (case % 3 == 0).then_some(20),
+                ..Scoring::default()
+            };
+            let adapter_len = 5 + rng.below(60);
+            check_striped(&mut rng, &scoring, adapter_len, 0.2);
+        }
+    }
+
+    #[test]
+    fn striped_matches_full_matrix_with_cheap_long_gaps() {
+        // Cheap gaps and high indel rates make adapter gaps run across lane
+        // boundaries, which only the lazy-F loop picks up.
+        let mut rng = Rng(42);
+        let scoring = Scoring { match_score: 3, mismatch_penalty: 3, gap_open: 1, gap_extend: 1, mismatch_quality_cap: None, match_read_wildcards: false };
+        for _ in 0..150 {
+            let adapter_len = 30 + rng.below(50);
+            check_striped(&mut rng, &scoring, adapter_len, 0.4);
+        }
+    }
+
+    #[test]
+    fn lazy_f_carries_gap_across_lanes() {
+        // Deleting the middle of a 64-base adapter from the read forces an
+        // adapter gap that spans several lanes.
+        let mut rng = Rng(7);
+        let adapter = rng.bases(64);
+        let mut read = rng.bases(50);
+        read.extend(&adapter[..20]);
+        read.extend(&adapter[44..]);
+        read.extend(rng.bases(50));
+        let scoring = Scoring { match_score: 2, mismatch_penalty: 4, gap_open: 3, gap_extend: 1, mismatch_quality_cap: None, match_read_wildcards: false };
+        let (best, row) = striped_best_row(&read, &[], &adapter, &scoring).unwrap();
+        assert_eq!((best, row), full_best(&read, &[], &adapter, &scoring));
+        assert_eq!((best, row), (2 * 40 - 3 - 23, 50 + 40));
+    }
+
+    #[test]
+    fn traceback_window_covers_worst_case_path() {
+        // A weak hit with a long read insertion needs the widest window.
+        let scoring = Scoring { match_score: 3, mismatch_penalty: 3, gap_open: 1, gap_extend: 1, mismatch_quality_cap: None, match_read_wildcards: false };
+        let mut read = b"GGGGGGGGGG".to_vec();
+        read.extend(b"ACGTTG");
+        read.extend(b"TTTTTTTTTT");
+        read.extend(b"CAAGTC");
+        read.extend(b"GGGGGGGGGG");
+        let sequence = String::from_utf8(read.clone()).unwrap();
+        let hit = smith_waterman_align(&sequence, "", ADAPTER, &scoring).unwrap();
+        assert_eq!((hit.read_start, hit.read_end, hit.indels), (10, 32, 10));
+        assert_eq!(traceback_window(ADAPTER.len(), hit.score, &scoring), ADAPTER.len() + 10 + 2);
+        assert_eq!(Some(hit), full_matrix_align(&read, &[], ADAPTER.as_bytes(), &scoring));
+    }
+
+    #[test]
+    fn anchored_overlap_finds_adapter_suffix_at_read_start() {
+        let read = b"TGCAAGTCGGATCCGGATTACA";
+        let hit = anchored_overlap(read, ADAPTER.as_bytes(), 5, 0.1, false).unwrap();
+        assert_eq!(hit, Overlap { read_len: 8, adapter_len: 8, matches: 8, errors: 0 });
+    }
+
+    #[test]
+    fn anchored_overlap_allows_errors_within_rate() {
+        // One substitution in a 10-base overlap passes at 0.1 but not 0.05.
+        let read = b"GTTGCATGTCGGATCCGGATTACA";
+        let hit = anchored_overlap(read, ADAPTER.as_bytes(), 5, 0.1, false).unwrap();
+        assert_eq!((hit.read_len, hit.adapter_len, hit.matches, hit.errors), (10, 10, 9, 1));
+        assert_eq!(anchored_overlap(read, ADAPTER.as_bytes(), 5, 0.05, false), None);
+    }
+
+    #[test]
+    fn anchored_overlap_respects_min_overlap() {
+        let read = b"GTCGGATCCGGATTACA";
+        assert_eq!(anchored_overlap(read, ADAPTER.as_bytes(), 4, 0.1, false), None);
+        assert_eq!(anchored_overlap(read, ADAPTER.as_bytes(), 3, 0.1, false).map(|hit| hit.read_len), Some(3));
+    }
+
+    #[test]
+    fn anchored_overlap_only_reads_its_span() {
+        let mut rng = Rng(11);
+        for _ in 0..200 {
+            let adapter_len = 10 + rng.below(30);
+            let adapter = rng.bases(adapter_len);
+            let rate = rng.below(40) as f64 / 100.0;
+            let cut = rng.below(adapter.len());
+            let tail_len = 50 + rng.below(100);
+            let mut read = rng.mutate(&adapter[cut..], rate);
+            read.extend(rng.bases(tail_len));
+            let span = read.len().min(overlap_span(adapter.len(), rate));
+            assert_eq!(
+                anchored_overlap(&read, &adapter, 4, rate, false),
+                anchored_overlap(&read[..span], &adapter, 4, rate, false),
+            );
+        }
+    }
+
+    #[test]
+    fn iupac_codes_cover_their_bases() {
+        let expected = [
+            (b'A', "A"), (b'C', "C"), (b'G', "G"), (b'T', "T"), (b'U', "T"),
+            (b'R', "AG"), (b'Y', "CT"), (b'S', "CG"), (b'W', "AT"), (b'K', "GT"), (b'M', "AC"),
+            (b'B', "CGT"), (b'D', "AGT"), (b'H', "ACT"), (b'V', "ACG"), (b'N', "ACGT"),
+        ];
+        for (code, bases) in expected {
+            let ambiguous = bases.len() > 1;
+            for base in *b"ACGT" {
+                let covered = bases.as_bytes().contains(&base);
+                assert_eq!(bases_match(base, code, false), covered, "{} vs {}", base as char, code as char);
+                assert_eq!(bases_match(base, code.to_ascii_lowercase(), false), covered);
+                assert_eq!(bases_match(code, base, true), covered);
+                // Ambiguity codes in the read only match when asked to.
+                assert_eq!(bases_match(code, base, false), covered && !ambiguous);
+            }
+        }
+    }
+
+    #[test]
+    fn non_nucleotides_match_nothing() {
+        assert_eq!(iupac_mask(b'-'), 0);
+        assert_eq!(iupac_mask(b'X'), 0);
+        assert!(!bases_match(b'.', b'N', true));
+        assert!(bases_match(b'n', b'r', true));
+        assert!(!bases_match(b'n', b'r', false));
+        assert!(!bases_match(b'R', b'Y', true));
+    }
+
+    #[test]
+    fn uncalled_reads_are_not_adapters() {
+        let read = format!("GGCC{}CCGG", "N".repeat(24));
+        let hit = smith_waterman_align(&read, "", ADAPTER, &Scoring::default());
+        assert!(hit.is_none_or(|hit| hit.matches <= 2), "{:?}", hit);
+        assert_eq!(anchored_overlap(read.as_bytes(), ADAPTER.as_bytes(), 4, 0.1, false), None);
+
+        let wildcards = Scoring { match_read_wildcards: true, ..Scoring::default() };
+        let hit = smith_waterman_align(&read, "", ADAPTER, &wildcards).unwrap();
+        assert_eq!((hit.matches, hit.mismatches, hit.indels), (ADAPTER.len(), 0, 0));
+    }
+
+    #[test]
+    fn iupac_adapter_aligns_as_wildcard() {
+        let read = "TTTTTacgttgcaagtcGGGGG";
+        let hit = smith_waterman_align(read, "", "ACGNNGCARGTC", &Scoring::default()).unwrap();
+        assert_eq!((hit.score, hit.matches, hit.mismatches), (24, 12, 0));
+    }
+
+    #[test]
+    fn unrelated_read_has_no_hit() {
+        assert_eq!(smith_waterman_align("GGGGGGGGGGGGGGGG", "", "ACTACT", &Scoring::default()), None);
+    }
+}
@@ -0,0 +1,217 @@
+//! Amplicon mode: reads must carry a forward and a reverse primer of the
+//! same pair at opposite ends. Primers are trimmed and reads from the minus
+//! strand are reverse complemented so all amplicons read forward.
+
+use std::io::BufRead;
+use std::path::Path;
+
+use crate::align::{smith_waterman_align, Alignment};
+use crate::{open_input, reverse_complement, AdapterConfig};
+
+/// A primer pair from `--primers`, both primers written 5' to 3'.
+#[derive(Clone, Debug)]
+pub struct PrimerPair {
+    pub name: String,
+    pub forward: String,
+    pub reverse: String,
+}
+
+pub struct AmpliconConfig {
+    pub pairs: Vec<PrimerPair>,
+    /// Scoring and acceptance thresholds for primer hits.
+    pub alignment: AdapterConfig,
+    /// Number of bases at each read end searched for primers.
+    pub search_window: usize,
+    /// Allowed insert length between the primers.
+    pub min_length: usize,
+    pub max_length: Option<usize>,
+}
+
+/// Primer pair found in a read.
+#[derive(Clone, Copy, Debug)]
+pub struct AmpliconHit {
+    /// Index into [`AmpliconConfig::pairs`].
+    pub pair: usize,
+    /// Insert between the primers, in read coordinates.
+    pub insert_start: usize,
+    pub insert_end: usize,
+    /// The read starts with the reverse primer and has to be reverse
+    /// complemented to read forward.
+    pub minus_strand: bool,
+}
+
+/// Reads a tab-separated `name forward reverse` table. Blank lines and
+/// lines starting with `#` are skipped.
+pub fn load_primers(path: &Path) -> Result<Vec<PrimerPair>, String> {
+    read_primers(open_input(path).map_err(|e| e.to_string())?)
+}
+
+fn read_primers(reader: impl BufRead) -> Result<Vec<PrimerPair>, String> {
+    let mut pairs = Vec::new();
+    for (number, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').map(str::trim).collect();
+        match fields.as_slice() {
+            [name, forward, reverse] => pairs.push(PrimerPair {
+                name: name.to_string(),
+                forward: forward.to_string(),
+                reverse: reverse.to_string(),
+            }),
+            _ => return Err(format!("line {}: expected 3 tab-separated columns: name, forward, reverse", number + 1)),
+        }
+    }
+    Ok(pairs)
+}
+
+impl AmpliconConfig {
+    /// Hit of `primer` in `sequence[start..end]` if it passes the thresholds.
+    fn find_primer(&self, sequence: &str, quality: &str, primer: &str, start: usize, end: usize) -> Option<Alignment> {
+        let region_quality = quality.get(start..end).unwrap_or("");
+        let mut hit = smith_waterman_align(&sequence[start..end], region_quality, primer, &self.alignment.scoring)?;
+        if !self.alignment.accepts_full_length(&hit, primer.len()) {
+            return None;
+        }
+        hit.read_start += start;
+        hit.read_end += start;
+        Some(hit)
+    }
+
+    /// Finds the best-scoring primer pair with one primer forward at the 5'
+    /// end and the other reverse complemented at the 3' end.
+    pub fn find(&self, sequence: &str, quality: &str) -> Option<AmpliconHit> {
+        let len = sequence.len();
+        let window = self.search_window.min(len / 2);
+        let mut best: Option<(i32, AmpliconHit)> = None;
+
+        for (index, pair) in self.pairs.iter().enumerate() {
+            for (first, second, minus_strand) in [(&pair.forward, &pair.reverse, false), (&pair.reverse, &pair.forward, true)] {
+                let Some(head) = self.find_primer(sequence, quality, first, 0, window) else { continue };
+                let Some(tail) = self.find_primer(sequence, quality, &reverse_complement(second), len - window, len) else { continue };
+                let score = head.score + tail.score;
+                if best.is_none_or(|(best_score, _)| score > best_score) {
+                    let hit = AmpliconHit { pair: index, insert_start: head.read_end, insert_end: tail.read_start, minus_strand };
+                    best = Some((score, hit));
+                }
+            }
+        }
+        best.map(|(_, hit)| hit)
+    }
+
+    pub fn in_length_window(&self, insert_length: usize) -> bool {
+        insert_length >= self.min_length && self.max_length.is_none_or(|max| insert_length <= max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::align::Scoring;
+
+    const FORWARD: &str = "AGAGTTTGATCCTGGCTCAG";
+    const REVERSE: &str = "TACGGTTACCTTGTTACGAC";
+    const INSERT: &str = "GATTACAGATTACACCATGGTTAACCGGTTAACCATGGATCCATGCATGCAAGCTTGCATGC";
+
+    fn pair(name: &str, forward: &str, reverse: &str) -> PrimerPair {
+        PrimerPair { name: name.to_string(), forward: forward.to_string(), reverse: reverse.to_string() }
+    }
+
+    fn config(pairs: Vec<PrimerPair>, min_length: usize, max_length: Option<usize>) -> AmpliconConfig {
+        AmpliconConfig {
+            pairs,
+            alignment: AdapterConfig {
+                scoring: Scoring::default(),
+                min_match: 0,
+                max_mismatches: None,
+                max_indels: None,
+                max_error_rate: Some(0.1),
+                min_score: 0,
+                min_identity: 0.0,
+                search_window: None,
+                internal_scan: false,
+                min_end_overlap: None,
+                end_error_rate: 0.1,
+                umi_pattern: None,
+                orient_reads: false,
+                polyx_bases: Vec::new(),
+                polyx_min_length: 10,
+                polyx_error_rate: 0.1,
+            },
+            search_window: 60,
+            min_length,
+            max_length,
+        }
+    }
+
+    /// An amplicon of the 16S-like pair as read from the plus strand.
+    fn plus_read() -> String {
+        format!("TTTT{FORWARD}{INSERT}{}CCCC", reverse_complement(REVERSE))
+    }
+
+    #[test]
+    fn plus_strand_read_gives_the_insert() {
+        let amplicon = config(vec![pair("16S", FORWARD, REVERSE)], 0, None);
+        let read = plus_read();
+        let hit = amplicon.find(&read, "").unwrap();
+        assert_eq!((hit.pair, hit.minus_strand), (0, false));
+        assert_eq!(&read[hit.insert_start..hit.insert_end], INSERT);
+    }
+
+    #[test]
+    fn minus_strand_read_gives_the_reverse_complemented_insert() {
+        let amplicon = config(vec![pair("16S", FORWARD, REVERSE)], 0, None);
+        let read = reverse_complement(&plus_read());
+        let hit = amplicon.find(&read, "").unwrap();
+        assert!(hit.minus_strand);
+        assert_eq!(reverse_complement(&read[hit.insert_start..hit.insert_end]), INSERT);
+    }
+
+    #[test]
+    fn both_primers_are_required() {
+        let amplicon = config(vec![pair("16S", FORWARD, REVERSE)], 0, None);
+        let no_reverse = format!("TTTT{FORWARD}{INSERT}CCCC");
+        let no_forward = format!("TTTT{INSERT}{}CCCC", reverse_complement(REVERSE));
+        assert!(amplicon.find(&no_reverse, "").is_none());
+        assert!(amplicon.find(&no_forward, "").is_none());
+        // Both primers at the same end is not an amplicon either.
+        let same_end = format!("TTTT{FORWARD}{REVERSE}{INSERT}CCCC");
+        assert!(amplicon.find(&same_end, "").is_none());
+    }
+
+    #[test]
+    fn insert_length_window() {
+        let amplicon = config(vec![pair("16S", FORWARD, REVERSE)], 50, Some(70));
+        assert!(amplicon.in_length_window(INSERT.len()));
+        assert!(!amplicon.in_length_window(49));
+        assert!(!amplicon.in_length_window(71));
+        assert!(config(Vec::new(), 0, None).in_length_window(usize::MAX));
+    }
+
+    #[test]
+    fn best_scoring_pair_wins() {
+        // The decoy shares most of each primer but not all of it.
+        let decoy = pair("decoy", "AGAGTTTGATCCAAGCTCAG", "TACGGTTACCTTCCTACGAC");
+        assert!(config(vec![decoy.clone()], 0, None).find(&plus_read(), "").is_some());
+        let amplicon = config(vec![decoy.clone(), pair("16S", FORWARD, REVERSE)], 0, None);
+        assert_eq!(amplicon.find(&plus_read(), "").map(|hit| hit.pair), Some(1));
+        let amplicon = config(vec![pair("16S", FORWARD, REVERSE), decoy], 0, None);
+        assert_eq!(amplicon.find(&plus_read(), "").map(|hit| hit.pair), Some(0));
+    }
+
+    #[test]
+    fn primer_table_lines() {
+        let table = "# name\tforward\treverse\n\n16S\tAGAGTTTGATCMTGGCTCAG\t TACGGYTACCTTGTTACGACTT \nITS\tCTTGGTCATTTAGAGGAAGTAA\tGCTGCGTTCTTCATCGATGC\n";
+        let pairs = read_primers(table.as_bytes()).unwrap();
+        assert_eq!(pairs.len(), 2);
+        assert_eq!((pairs[0].name.as_str(), pairs[0].reverse.as_str()), ("16S", "TACGGYTACCTTGTTACGACTT"));
+
+        let err = read_primers("16S\tAGAGTTTGATCMTGGCTCAG\nITS\tA\tC\n".as_bytes()).unwrap_err();
+        assert!(err.starts_with("line 1:"), "{}", err);
+        let err = read_primers("ok\tA\tC\n16S AGAG TACG\n".as_bytes()).unwrap_err();
+        assert!(err.starts_with("line 2:"), "{}", err);
+        assert!(read_primers("16S\tA\tC\textra\n".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,210 @@
+//! cDNA read classification in the manner of pychopper. A full-length read
+//! reads SSP, transcript, reverse-complemented VNP (or the reverse
+//! complement of that); primers are trimmed and reads are oriented to the
+//! transcript strand.
+
+use crate::align::smith_waterman_align;
+use crate::{reverse_complement, AdapterConfig};
+
+pub struct CdnaConfig {
+    /// Strand-switching primer, on the 5' end of the transcript.
+    pub ssp: String,
+    /// Anchored oligo-dT primer, on the 3' end of the transcript.
+    pub vnp: String,
+    /// Scoring and acceptance thresholds for primer hits.
+    pub alignment: AdapterConfig,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CdnaClass {
+    /// Exactly one primer at each end, in a valid combination.
+    FullLength,
+    /// Extra internal primer hits, but at least one valid segment.
+    Rescued,
+    Unusable,
+}
+
+impl CdnaClass {
+    pub fn label(self) -> &'static str {
+        match self {
+            CdnaClass::FullLength => "full-length",
+            CdnaClass::Rescued => "rescued",
+            CdnaClass::Unusable => "unusable",
+        }
+    }
+}
+
+/// Part of a read between a valid pair of primer hits, primers excluded.
+#[derive(Clone, Copy, Debug)]
+pub struct Segment {
+    pub start: usize,
+    pub end: usize,
+    /// The segment reads VNP first and is reverse complemented to the
+    /// transcript strand.
+    pub minus_strand: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Primer {
+    Ssp,
+    Vnp,
+}
+
+#[derive(Clone, Copy)]
+struct PrimerHit {
+    primer: Primer,
+    reverse_complement: bool,
+    start: usize,
+    end: usize,
+}
+
+impl CdnaConfig {
+    /// All non-overlapping primer hits in `sequence[start..end]`: the best
+    /// hit is taken, then the parts on either side are searched again.
+    fn find_hits(&self, sequence: &str, quality: &str, start: usize, end: usize, hits: &mut Vec<PrimerHit>) {
+        let rev_ssp = reverse_complement(&self.ssp);
+        let rev_vnp = reverse_complement(&self.vnp);
+        let queries = [
+            (Primer::Ssp, false, self.ssp.as_str()),
+            (Primer::Ssp, true, rev_ssp.as_str()),
+            (Primer::Vnp, false, self.vnp.as_str()),
+            (Primer::Vnp, true, rev_vnp.as_str()),
+        ];
+
+        let region = &sequence[start..end];
+        let region_quality = quality.get(start..end).unwrap_or("");
+        let best = queries
+            .iter()
+            .filter_map(|&(primer, reverse_complement, query)| {
+                let hit = smith_waterman_align(region, region_quality, query, &self.alignment.scoring)?;
+                if !self.alignment.accepts_full_length(&hit, query.len()) {
+                    return None;
+                }
+                Some((hit.score, PrimerHit { primer, reverse_complement, start: start + hit.read_start, end: start + hit.read_end }))
+            })
+            .max_by_key(|(score, _)| *score);
+
+        if let Some((_, hit)) = best {
+            self.find_hits(sequence, quality, start, hit.start, hits);
+            hits.push(hit);
+            self.find_hits(sequence, quality, hit.end, end, hits);
+        }
+    }
+
+    /// Classifies a read and returns its valid segments in read order.
+    pub fn classify(&self, sequence: &str, quality: &str) -> (CdnaClass, Vec<Segment>) {
+        let mut hits = Vec::new();
+        self.find_hits(sequence, quality, 0, sequence.len(), &mut hits);
+
+        let segments: Vec<Segment> = hits
+            .windows(2)
+            .filter_map(|pair| {
+                let (left, right) = (pair[0], pair[1]);
+                let minus_strand = match (left.primer, right.primer) {
+                    (Primer::Ssp, Primer::Vnp) => false,
+                    (Primer::Vnp, Primer::Ssp) => true,
+                    _ => return None,
+                };
+                (!left.reverse_complement && right.reverse_complement)
+                    .then_some(Segment { start: left.end, end: right.start, minus_strand })
+            })
+            .collect();
+
+        let class = match (segments.len(), hits.len()) {
+            (0, _) => CdnaClass::Unusable,
+            (1, 2) => CdnaClass::FullLength,
+            _ => CdnaClass::Rescued,
+        };
+        (class, segments)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::align::Scoring;
+
+    const SSP: &str = "TTTCTGTTGGTGCTGATATTGCTGGG";
+    const VNP: &str = "ACTTGCCTGTCGCTCTATCTTC";
+    const TRANSCRIPT: &str = "GATTACAGATTACACCATGGTTAACCGGTTAACCATGGATCCATGCATGCAAGCTTGCATGC";
+    const OTHER: &str = "CCGTAGGCATAGCCGATTCGGACTAAGCTTAGGCCTAGGATCGATCGAATTCCGGAAGGTCA";
+
+    fn config() -> CdnaConfig {
+        CdnaConfig {
+            ssp: SSP.to_string(),
+            vnp: VNP.to_string(),
+            alignment: AdapterConfig {
+                scoring: Scoring::default(),
+                min_match: 0,
+                max_mismatches: None,
+                max_indels: None,
+                max_error_rate: Some(0.1),
+                min_score: 0,
+                min_identity: 0.0,
+                search_window: None,
+                internal_scan: false,
+                min_end_overlap: None,
+                end_error_rate: 0.1,
+                umi_pattern: None,
+                orient_reads: false,
+                polyx_bases: Vec::new(),
+                polyx_min_length: 10,
+                polyx_error_rate: 0.1,
+            },
+        }
+    }
+
+    /// A transcript between its primers as read from the plus strand.
+    fn plus(transcript: &str) -> String {
+        format!("{SSP}{transcript}{}", reverse_complement(VNP))
+    }
+
+    fn segment_sequences(read: &str, segments: &[Segment]) -> Vec<String> {
+        segments
+            .iter()
+            .map(|segment| {
+                let sequence = &read[segment.start..segment.end];
+                if segment.minus_strand { reverse_complement(sequence) } else { sequence.to_string() }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn full_length_plus_strand() {
+        let read = format!("AAT{}GC", plus(TRANSCRIPT));
+        let (class, segments) = config().classify(&read, "");
+        assert_eq!(class, CdnaClass::FullLength);
+        assert_eq!(segments.len(), 1);
+        assert!(!segments[0].minus_strand);
+        assert_eq!(segment_sequences(&read, &segments), [TRANSCRIPT]);
+    }
+
+    #[test]
+    fn full_length_minus_strand() {
+        let read = reverse_complement(&plus(TRANSCRIPT));
+        let (class, segments) = config().classify(&read, "");
+        assert_eq!(class, CdnaClass::FullLength);
+        assert!(segments[0].minus_strand);
+        assert_eq!(segment_sequences(&read, &segments), [TRANSCRIPT]);
+    }
+
+    #[test]
+    fn concatenated_transcripts_are_rescued() {
+        // A plus-strand and a minus-strand transcript back to back.
+        let read = format!("{}{}", plus(TRANSCRIPT), reverse_complement(&plus(OTHER)));
+        let (class, segments) = config().classify(&read, "");
+        assert_eq!(class, CdnaClass::Rescued);
+        assert_eq!(segments.iter().map(|segment| segment.minus_strand).collect::<Vec<_>>(), [false, true]);
+        assert_eq!(segment_sequences(&read, &segments), [TRANSCRIPT, OTHER]);
+    }
+
+    #[test]
+    fn reads_without_a_primer_pair_are_unusable() {
+        let cdna = config();
+        assert_eq!(cdna.classify(TRANSCRIPT, "").0, CdnaClass::Unusable);
+        assert_eq!(cdna.classify(&format!("{SSP}{TRANSCRIPT}"), "").0, CdnaClass::Unusable);
+        assert_eq!(cdna.classify(&format!("{TRANSCRIPT}{}", reverse_complement(VNP)), "").0, CdnaClass::Unusable);
+        // Two primers in an invalid combination.
+        assert_eq!(cdna.classify(&format!("{SSP}{TRANSCRIPT}{}", reverse_complement(SSP)), "").0, CdnaClass::Unusable);
+    }
+}
@@ -0,0 +1,169 @@
+//! Low-complexity filtering with DUST or trinucleotide entropy, scored over
+//! 64-base windows as prinseq does. Both scores run from 0 to 100: entropy
+//! is low and DUST is high for simple repeats.
+
+const WINDOW: usize = 64;
+const STEP: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ComplexityMethod {
+    Dust,
+    Entropy,
+}
+
+pub struct ComplexityFilter {
+    pub method: ComplexityMethod,
+    /// Minimum entropy, or maximum DUST score.
+    pub threshold: f64,
+    /// Mask low-complexity windows with `N` instead of rejecting the read.
+    pub mask: bool,
+}
+
+/// Trinucleotide counts of a window and the number of trinucleotides
+/// counted; those with a non-ACGT base are skipped.
+fn triplet_counts(window: &[u8]) -> ([u32; 64], usize) {
+    let code = |base: u8| match base.to_ascii_uppercase() {
+        b'A' => Some(0),
+        b'C' => Some(1),
+        b'G' => Some(2),
+        b'T' | b'U' => Some(3),
+        _ => None,
+    };
+    let mut counts = [0; 64];
+    let mut total = 0;
+    for triplet in window.windows(3) {
+        if let (Some(a), Some(b), Some(c)) = (code(triplet[0]), code(triplet[1]), code(triplet[2])) {
+            counts[a * 16 + b * 4 + c] += 1;
+            total += 1;
+        }
+    }
+    (counts, total)
+}
+
+/// Symmetric DUST score, scaled so a homopolymer scores 100.
+fn dust_score(window: &[u8]) -> f64 {
+    let (counts, total) = triplet_counts(window);
+    if total < 2 {
+        return 0.0;
+    }
+    let score: f64 = counts.iter().map(|&c| (c * c.saturating_sub(1)) as f64 / 2.0).sum::<f64>() / (total - 1) as f64;
+    score * 100.0 / (total as f64 / 2.0)
+}
+
+/// Trinucleotide Shannon entropy, scaled so a window with all possible
+/// trinucleotides equally often scores 100.
+fn entropy_score(window: &[u8]) -> f64 {
+    let (counts, total) = triplet_counts(window);
+    if total < 2 {
+        return 0.0;
+    }
+    let entropy: f64 = counts
+        .iter()
+        .filter(|&&c| c > 0)
+        .map(|&c| {
+            let p = c as f64 / total as f64;
+            p * (1.0 / p).ln()
+        })
+        .sum();
+    entropy * 100.0 / (total.min(64) as f64).ln()
+}
+
+impl ComplexityFilter {
+    /// Window ranges; reads shorter than a window are one window. When the
+    /// steps do not land on the read end, a last window ends there so the
+    /// tail bases are scored too.
+    fn windows(len: usize) -> impl Iterator<Item = (usize, usize)> {
+        let last = len.saturating_sub(WINDOW);
+        let tail = (!last.is_multiple_of(STEP)).then_some(last);
+        (0..=last).step_by(STEP).chain(tail).map(move |start| (start, (start + WINDOW).min(len)))
+    }
+
+    fn window_score(&self, window: &[u8]) -> f64 {
+        match self.method {
+            ComplexityMethod::Dust => dust_score(window),
+            ComplexityMethod::Entropy => entropy_score(window),
+        }
+    }
+
+    fn is_low(&self, score: f64) -> bool {
+        match self.method {
+            ComplexityMethod::Dust => score > self.threshold,
+            ComplexityMethod::Entropy => score < self.threshold,
+        }
+    }
+
+    /// Mean window score of a read.
+    pub fn score(&self, sequence: &str) -> f64 {
+        let seq = sequence.as_bytes();
+        let scores: Vec<f64> = Self::windows(seq.len()).map(|(start, end)| self.window_score(&seq[start..end])).collect();
+        scores.iter().sum::<f64>() / scores.len() as f64
+    }
+
+    pub fn is_low_complexity(&self, sequence: &str) -> bool {
+        self.is_low(self.score(sequence))
+    }
+
+    /// Replaces low-complexity windows with `N`s and returns the number of
+    /// bases masked.
+    pub fn mask(&self, sequence: &mut String) -> usize {
+        let mut seq = std::mem::take(sequence).into_bytes();
+        let mut masked = vec![false; seq.len()];
+        for (start, end) in Self::windows(seq.len()) {
+            if self.is_low(self.window_score(&seq[start..end])) {
+                masked[start..end].iter_mut().for_each(|m| *m = true);
+            }
+        }
+        let mut count = 0;
+        for (base, &mask) in seq.iter_mut().zip(&masked) {
+            if mask && *base != b'N' {
+                *base = b'N';
+                count += 1;
+            }
+        }
+        *sequence = String::from_utf8(seq).unwrap();
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(method: ComplexityMethod, threshold: f64) -> ComplexityFilter {
+        ComplexityFilter { method, threshold, mask: true }
+    }
+
+    /// A pseudo-random read.
+    fn complex(len: usize) -> String {
+        let mut state: u32 = 12345;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                b"ACGT"[(state >> 16) as usize % 4] as char
+            })
+            .collect()
+    }
+
+    #[test]
+    fn windows_cover_the_whole_read() {
+        assert_eq!(ComplexityFilter::windows(40).collect::<Vec<_>>(), [(0, 40)]);
+        assert_eq!(ComplexityFilter::windows(128).collect::<Vec<_>>(), [(0, 64), (32, 96), (64, 128)]);
+        assert_eq!(ComplexityFilter::windows(140).collect::<Vec<_>>(), [(0, 64), (32, 96), (64, 128), (76, 140)]);
+    }
+
+    #[test]
+    fn simple_repeats_score_as_low_complexity() {
+        let repeat = "A".repeat(100);
+        assert!(filter(ComplexityMethod::Dust, 7.0).is_low_complexity(&repeat));
+        assert!(filter(ComplexityMethod::Entropy, 70.0).is_low_complexity(&repeat));
+        assert!(!filter(ComplexityMethod::Entropy, 70.0).is_low_complexity(&complex(100)));
+    }
+
+    #[test]
+    fn tail_repeat_is_masked() {
+        let mut read = complex(128) + &"A".repeat(20);
+        let masked = filter(ComplexityMethod::Dust, 7.0).mask(&mut read);
+        assert!(masked >= 20, "{} bases masked", masked);
+        assert!(read.ends_with(&"N".repeat(20)));
+    }
+}
@@ -0,0 +1,339 @@
+//! Duplicate read removal with `--dedup`. Only reads that pass every other
+//! filter are checked, in input order, so the first copy that would be
+//! written is kept; keys come from the input read, before any trimming.
+//!
+//! `ids` and `exact` keep 128-bit hashes of read IDs or sequences in a hash
+//! set, or in a Bloom filter of fixed size with `--dedup-memory`, which can
+//! drop a few unique reads as false positives. `minhash` compares MinHash
+//! sketches of 12-mers and keeps one sketch, about 2 KB with its index
+//! entries, per kept read; `--dedup-memory` caps the number of sketches,
+//! after which new reads are only compared with those already indexed.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+
+use rayon::prelude::*;
+
+use crate::ids::read_id;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DedupMode {
+    /// Repeated read IDs, e.g. from chunk files concatenated twice.
+    Ids,
+    /// Identical sequences.
+    Exact,
+    /// Sequences whose 12-mer Jaccard similarity reaches a threshold.
+    MinHash,
+}
+
+impl DedupMode {
+    pub fn label(self) -> &'static str {
+        match self {
+            DedupMode::Ids => "read ID",
+            DedupMode::Exact => "sequence",
+            DedupMode::MinHash => "near-identical sequence",
+        }
+    }
+}
+
+pub struct DedupConfig {
+    pub mode: DedupMode,
+    /// Bloom filter size in bytes for `ids` and `exact`, or the sketch
+    /// index limit for `minhash`.
+    pub memory: Option<usize>,
+    /// Minimum estimated Jaccard similarity for `minhash`.
+    pub similarity: f64,
+}
+
+fn hash128(data: &str) -> u128 {
+    let hash = |seed: u64| {
+        let mut hasher = DefaultHasher::new();
+        seed.hash(&mut hasher);
+        data.hash(&mut hasher);
+        hasher.finish()
+    };
+    ((hash(0) as u128) << 64) | hash(1) as u128
+}
+
+fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+const BLOOM_HASHES: u64 = 7;
+
+struct BloomFilter {
+    bits: Vec<u64>,
+}
+
+impl BloomFilter {
+    fn with_bytes(bytes: usize) -> BloomFilter {
+        BloomFilter { bits: vec![0; (bytes / 8).max(1)] }
+    }
+
+    /// Sets the bits of `key` and returns whether any was unset.
+    fn insert(&mut self, key: u128) -> bool {
+        let num_bits = self.bits.len() as u64 * 64;
+        let (h1, h2) = (key as u64, (key >> 64) as u64 | 1);
+        let mut new = false;
+        for i in 0..BLOOM_HASHES {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % num_bits;
+            let (word, mask) = ((bit / 64) as usize, 1 << (bit % 64));
+            if self.bits[word] & mask == 0 {
+                self.bits[word] |= mask;
+                new = true;
+            }
+        }
+        new
+    }
+}
+
+enum Seen {
+    Set(HashSet<u128>),
+    Bloom(BloomFilter),
+}
+
+impl Seen {
+    /// Adds a key and returns whether it was new.
+    fn insert(&mut self, key: u128) -> bool {
+        match self {
+            Seen::Set(set) => set.insert(key),
+            Seen::Bloom(bloom) => bloom.insert(key),
+        }
+    }
+}
+
+const KMER: usize = 12;
+const SKETCH_SIZE: usize = 64;
+/// Sketches sharing all values of any band are compared; two rows per band
+/// make reads of similarity 0.5 candidates with near certainty.
+const BANDS: usize = 32;
+const ROWS: usize = SKETCH_SIZE / BANDS;
+const EMPTY: u32 = u32::MAX;
+/// Memory per indexed sketch: the sketch and its band entries.
+pub const SKETCH_BYTES: usize = 2048;
+/// Most sketches listed under one band key. Near-identical reads stop at
+/// the first indexed copy, but many reads that share bands without being
+/// similar enough, as amplicons do, would otherwise make every insert
+/// compare against all earlier reads.
+const MAX_BUCKET: usize = 32;
+
+/// One-permutation MinHash: each k-mer hash falls into one bin, which keeps
+/// the smallest value. K-mers with a non-ACGT base are skipped.
+fn sketch(sequence: &str) -> Box<[u32]> {
+    let mut sketch = vec![EMPTY; SKETCH_SIZE];
+    let mask = (1u64 << (2 * KMER)) - 1;
+    let mut kmer = 0u64;
+    let mut valid = 0;
+    for &base in sequence.as_bytes() {
+        let code = match base.to_ascii_uppercase() {
+            b'A' => 0,
+            b'C' => 1,
+            b'G' => 2,
+            b'T' | b'U' => 3,
+            _ => {
+                valid = 0;
+                continue;
+            }
+        };
+        kmer = ((kmer << 2) | code) & mask;
+        valid += 1;
+        if valid >= KMER {
+            let hash = splitmix64(kmer);
+            let bin = (hash % SKETCH_SIZE as u64) as usize;
+            sketch[bin] = sketch[bin].min((hash >> 32) as u32);
+        }
+    }
+    sketch.into_boxed_slice()
+}
+
+/// Estimated Jaccard similarity: matching bins over bins filled in either.
+fn similarity(a: &[u32], b: &[u32]) -> f64 {
+    let filled = a.iter().zip(b).filter(|&(&x, &y)| x != EMPTY || y != EMPTY).count();
+    if filled == 0 {
+        return 0.0;
+    }
+    let matching = a.iter().zip(b).filter(|&(&x, &y)| x == y && x != EMPTY).count();
+    matching as f64 / filled as f64
+}
+
+/// Keys of the bands of a sketch, leaving out empty bands so that short
+/// reads don't all collide.
+fn band_keys(sketch: &[u32]) -> Vec<u64> {
+    sketch
+        .chunks(ROWS)
+        .enumerate()
+        .filter(|(_, band)| band.iter().any(|&value| value != EMPTY))
+        .map(|(index, band)| band.iter().fold(index as u64, |key, &value| splitmix64(key ^ value as u64)))
+        .collect()
+}
+
+struct SketchIndex {
+    sketches: Vec<Box<[u32]>>,
+    bands: HashMap<u64, Vec<u32>>,
+    max_sketches: usize,
+}
+
+impl SketchIndex {
+    /// Adds a sketch unless a similar one is already indexed, and returns
+    /// whether one was. Full buckets, or a full index, take no more.
+    fn insert_unless_similar(&mut self, sketch: Box<[u32]>, threshold: f64) -> bool {
+        let keys = band_keys(&sketch);
+        let duplicate = keys
+            .iter()
+            .filter_map(|key| self.bands.get(key))
+            .flatten()
+            .any(|&index| similarity(&self.sketches[index as usize], &sketch) >= threshold);
+        if !duplicate && self.sketches.len() < self.max_sketches {
+            let index = self.sketches.len() as u32;
+            for key in keys {
+                let bucket = self.bands.entry(key).or_default();
+                if bucket.len() < MAX_BUCKET {
+                    bucket.push(index);
+                }
+            }
+            self.sketches.push(sketch);
+        }
+        duplicate
+    }
+}
+
+pub struct Deduplicator {
+    mode: DedupMode,
+    similarity: f64,
+    seen: Seen,
+    sketches: SketchIndex,
+}
+
+impl Deduplicator {
+    pub fn new(config: &DedupConfig) -> Deduplicator {
+        let minhash = config.mode == DedupMode::MinHash;
+        let seen = match config.memory {
+            Some(bytes) if !minhash => Seen::Bloom(BloomFilter::with_bytes(bytes)),
+            _ => Seen::Set(HashSet::new()),
+        };
+        let max_sketches = match config.memory {
+            Some(bytes) if minhash => bytes / SKETCH_BYTES,
+            _ => u32::MAX as usize,
+        };
+        let sketches = SketchIndex { sketches: Vec::new(), bands: HashMap::new(), max_sketches };
+        Deduplicator { mode: config.mode, similarity: config.similarity, seen, sketches }
+    }
+
+    /// Marks the duplicates among a batch of FASTQ lines, four per record,
+    /// against all earlier records. Only records with `candidates` set are
+    /// checked and remembered. Keys are computed in parallel and added in
+    /// input order.
+    pub fn find_duplicates(&mut self, lines: &[String], candidates: &[bool]) -> Vec<bool> {
+        let records = lines.par_chunks(4).zip(candidates);
+        match self.mode {
+            DedupMode::Ids | DedupMode::Exact => {
+                let mode = self.mode;
+                let keys: Vec<Option<u128>> = records
+                    .map(|(chunk, &candidate)| {
+                        candidate.then(|| if mode == DedupMode::Ids { hash128(read_id(&chunk[0])) } else { hash128(&chunk[1]) })
+                    })
+                    .collect();
+                keys.into_iter().map(|key| key.is_some_and(|key| !self.seen.insert(key))).collect()
+            }
+            DedupMode::MinHash => {
+                let sketches: Vec<Option<Box<[u32]>>> =
+                    records.map(|(chunk, &candidate)| candidate.then(|| sketch(&chunk[1]))).collect();
+                sketches
+                    .into_iter()
+                    .map(|sketch| sketch.is_some_and(|sketch| self.sketches.insert_unless_similar(sketch, self.similarity)))
+                    .collect()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// FASTQ lines for reads `(id, sequence)`.
+    fn fastq(reads: &[(&str, &str)]) -> Vec<String> {
+        reads.iter().flat_map(|&(id, sequence)| [format!("@{} ch=1", id), sequence.to_string(), "+".to_string(), "I".repeat(sequence.len())]).collect()
+    }
+
+    /// A pseudo-random sequence.
+    fn random_sequence(seed: u64, len: usize) -> String {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = splitmix64(state);
+                b"ACGT"[(state % 4) as usize] as char
+            })
+            .collect()
+    }
+
+    fn config(mode: DedupMode, memory: Option<usize>) -> DedupConfig {
+        DedupConfig { mode, memory, similarity: 0.5 }
+    }
+
+    #[test]
+    fn first_copy_is_kept() {
+        let read = random_sequence(1, 200);
+        let other = random_sequence(2, 200);
+        let lines = fastq(&[("r1", &read), ("r2", &other), ("r1", &other), ("r3", &read)]);
+        let all = [true; 4];
+        assert_eq!(Deduplicator::new(&config(DedupMode::Ids, None)).find_duplicates(&lines, &all), [false, false, true, false]);
+        assert_eq!(Deduplicator::new(&config(DedupMode::Exact, None)).find_duplicates(&lines, &all), [false, false, true, true]);
+        assert_eq!(Deduplicator::new(&config(DedupMode::Exact, Some(1 << 20))).find_duplicates(&lines, &all), [false, false, true, true]);
+    }
+
+    #[test]
+    fn only_candidates_are_remembered() {
+        let read = random_sequence(1, 200);
+        let lines = fastq(&[("r1", &read), ("r2", &read), ("r3", &read)]);
+        let mut deduplicator = Deduplicator::new(&config(DedupMode::Exact, None));
+        assert_eq!(deduplicator.find_duplicates(&lines, &[false, true, true]), [false, false, true]);
+        // Later batches see the kept copy.
+        assert_eq!(deduplicator.find_duplicates(&lines[..4], &[true]), [true]);
+    }
+
+    #[test]
+    fn near_identical_reads_are_duplicates() {
+        let read = random_sequence(1, 1000);
+        let mut similar = read.clone();
+        similar.replace_range(500..501, if &read[500..501] == "A" { "C" } else { "A" });
+        let lines = fastq(&[("r1", &read), ("r2", &similar), ("r3", &random_sequence(2, 1000))]);
+        let mut deduplicator = Deduplicator::new(&config(DedupMode::MinHash, None));
+        assert_eq!(deduplicator.find_duplicates(&lines, &[true; 3]), [false, true, false]);
+        assert!(similarity(&sketch(&read), &sketch(&similar)) > 0.9);
+        assert_eq!(similarity(&sketch("NNNN"), &sketch("ACGT")), 0.0);
+    }
+
+    #[test]
+    fn sketch_index_is_bounded() {
+        let reads: Vec<String> = (0..100).map(|seed| random_sequence(seed, 300)).collect();
+        let records: Vec<(&str, &str)> = reads.iter().map(|read| ("r", read.as_str())).collect();
+        let lines = fastq(&records);
+        let mut deduplicator = Deduplicator::new(&config(DedupMode::MinHash, Some(10 * SKETCH_BYTES)));
+        assert!(deduplicator.find_duplicates(&lines, &[true; 100]).iter().all(|&duplicate| !duplicate));
+        assert_eq!(deduplicator.sketches.sketches.len(), 10);
+
+        // Sketches that all share one band fill its bucket only up to the cap.
+        let mut index = SketchIndex { sketches: Vec::new(), bands: HashMap::new(), max_sketches: usize::MAX };
+        for value in 0..100 {
+            let mut sketch = vec![EMPTY; SKETCH_SIZE];
+            sketch[..ROWS].fill(7);
+            sketch[ROWS] = value;
+            index.insert_unless_similar(sketch.into_boxed_slice(), 0.9);
+        }
+        assert_eq!(index.sketches.len(), 100);
+        assert!(index.bands.values().all(|bucket| bucket.len() <= MAX_BUCKET));
+    }
+
+    #[test]
+    fn bloom_filter_reports_new_keys() {
+        let mut bloom = BloomFilter::with_bytes(1024);
+        assert!(bloom.insert(hash128("r1")));
+        assert!(bloom.insert(hash128("r2")));
+        assert!(!bloom.insert(hash128("r1")));
+    }
+}
@@ -0,0 +1,411 @@
+//! Barcode demultiplexing: each read goes to the sample whose barcode aligns
+//! best at either read end, or to the unclassified output when no barcode
+//! clearly wins. With dual barcoding a sample is a pair of barcodes, one at
+//! each end, and reads whose ends don't form a sample's pair are chimeras.
+
+use std::io::BufRead;
+use std::path::Path;
+
+use crate::align::{smith_waterman_align, Alignment};
+use crate::{open_input, reverse_complement, AdapterConfig};
+
+#[derive(Clone, Debug)]
+pub struct Barcode {
+    pub name: String,
+    pub sequence: String,
+}
+
+/// One demultiplexed output: the barcode expected forward at the 5' end and
+/// the one expected reverse complemented at the 3' end. Without dual
+/// barcoding every barcode is a sample of its own.
+#[derive(Clone, Debug)]
+pub struct Sample {
+    pub name: String,
+    /// Indices into [`DemuxConfig::barcodes`].
+    pub start: usize,
+    pub end: usize,
+}
+
+pub struct DemuxConfig {
+    pub barcodes: Vec<Barcode>,
+    pub samples: Vec<Sample>,
+    /// Scoring and acceptance thresholds for barcode hits.
+    pub alignment: AdapterConfig,
+    /// Number of bases at each read end searched for barcodes.
+    pub search_window: usize,
+    /// Minimum lead of the best barcode's score over any other barcode.
+    pub min_score_gap: i32,
+    /// Only assign reads with a sample's barcodes at both ends (dual
+    /// barcoding); assigned reads are trimmed and tagged.
+    pub require_both_ends: bool,
+    /// With `require_both_ends`, still assign reads with a single barcode.
+    pub allow_single_end: bool,
+    /// Adapter bases between the barcode and the insert, trimmed with it.
+    pub flank: usize,
+}
+
+/// Where a barcode aligned in a read.
+#[derive(Clone, Copy, Debug)]
+pub struct BarcodeHit {
+    /// Index into [`DemuxConfig::barcodes`].
+    pub barcode: usize,
+    pub alignment: Alignment,
+    pub reverse_complement: bool,
+    pub at_start: bool,
+}
+
+/// Outcome of barcode assignment for one read.
+#[derive(Clone, Copy, Debug)]
+pub enum BarcodeCall {
+    /// The sample's hits at the 5' and 3' ends, at least one of them set.
+    Assigned {
+        sample: usize,
+        start: Option<BarcodeHit>,
+        end: Option<BarcodeHit>,
+    },
+    /// With dual barcoding, the barcodes at the two ends are not the pair
+    /// of any sample.
+    Chimeric { start: usize, end: usize },
+    Unclassified,
+}
+
+/// Every barcode as its own sample, paired with itself.
+pub fn single_samples(barcodes: &[Barcode]) -> Vec<Sample> {
+    barcodes
+        .iter()
+        .enumerate()
+        .map(|(index, barcode)| Sample { name: barcode.name.clone(), start: index, end: index })
+        .collect()
+}
+
+/// Reads a tab-separated `sample 5'-barcode 3'-barcode` table naming
+/// entries of `barcodes`. Blank lines and lines starting with `#` are
+/// skipped.
+pub fn load_barcode_pairs(path: &Path, barcodes: &[Barcode]) -> Result<Vec<Sample>, String> {
+    let index = |name: &str| {
+        barcodes
+            .iter()
+            .position(|barcode| barcode.name == name)
+            .ok_or_else(|| format!("unknown barcode '{}'", name))
+    };
+    let mut samples: Vec<Sample> = Vec::new();
+    for (number, line) in open_input(path).map_err(|e| e.to_string())?.lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').map(str::trim).collect();
+        let [name, start, end] = fields.as_slice() else {
+            return Err(format!("line {}: expected 3 tab-separated columns: sample, 5' barcode, 3' barcode", number + 1));
+        };
+        let sample = Sample {
+            name: name.to_string(),
+            start: index(start).map_err(|e| format!("line {}: {}", number + 1, e))?,
+            end: index(end).map_err(|e| format!("line {}: {}", number + 1, e))?,
+        };
+        if let Some(other) = samples.iter().find(|other| other.start == sample.start && other.end == sample.end) {
+            return Err(format!("line {}: samples '{}' and '{}' have the same barcodes", number + 1, other.name, sample.name));
+        }
+        samples.push(sample);
+    }
+    Ok(samples)
+}
+
+impl DemuxConfig {
+    fn accepts(&self, hit: &BarcodeHit) -> bool {
+        let length = self.barcodes[hit.barcode].sequence.len();
+        self.alignment.accepts_full_length(&hit.alignment, length)
+    }
+
+    /// Best-scoring hit of every barcode in `sequence[start..end]`, whether
+    /// or not it passes the thresholds.
+    fn region_hits(&self, sequence: &str, quality: &str, start: usize, end: usize, at_start: bool) -> Vec<Option<BarcodeHit>> {
+        let region = &sequence[start..end];
+        let region_quality = quality.get(start..end).unwrap_or("");
+
+        self.barcodes
+            .iter()
+            .enumerate()
+            .map(|(index, barcode)| {
+                let rev_comp = reverse_complement(&barcode.sequence);
+                [(barcode.sequence.as_str(), false), (rev_comp.as_str(), true)]
+                    .into_iter()
+                    .filter_map(|(query, reverse_complement)| {
+                        let mut alignment = smith_waterman_align(region, region_quality, query, &self.alignment.scoring)?;
+                        alignment.read_start += start;
+                        alignment.read_end += start;
+                        Some(BarcodeHit { barcode: index, alignment, reverse_complement, at_start })
+                    })
+                    .max_by_key(|hit| (hit.alignment.score, std::cmp::Reverse(hit.reverse_complement)))
+            })
+            .collect()
+    }
+
+    /// The best hit, if it passes the thresholds and outscores every other
+    /// barcode by at least `min_score_gap`.
+    fn call(&self, hits: &[Option<BarcodeHit>]) -> Option<BarcodeHit> {
+        let winner = hits
+            .iter()
+            .flatten()
+            .max_by_key(|hit| (hit.alignment.score, std::cmp::Reverse(hit.barcode)))
+            .copied()?;
+        let runner_up = hits
+            .iter()
+            .flatten()
+            .filter(|hit| hit.barcode != winner.barcode)
+            .map(|hit| hit.alignment.score)
+            .max()
+            .unwrap_or(0);
+
+        if self.accepts(&winner) && winner.alignment.score - runner_up >= self.min_score_gap {
+            Some(winner)
+        } else {
+            None
+        }
+    }
+
+    /// Assigns a read, see [`DemuxConfig::assign_single`] and
+    /// [`DemuxConfig::assign_dual`].
+    pub fn assign(&self, sequence: &str, quality: &str) -> BarcodeCall {
+        if self.require_both_ends {
+            self.assign_dual(sequence, quality)
+        } else {
+            self.assign_single(sequence, quality)
+        }
+    }
+
+    /// Assigns a read to the barcode with the best hit at either end.
+    fn assign_single(&self, sequence: &str, quality: &str) -> BarcodeCall {
+        let len = sequence.len();
+        let window = self.search_window.min(len);
+        let mut best = self.region_hits(sequence, quality, 0, window, true);
+        // Reads shorter than the window were already searched in full.
+        if len > self.search_window {
+            for (best, tail) in best.iter_mut().zip(self.region_hits(sequence, quality, len - window, len, false)) {
+                if let Some(tail) = tail {
+                    if best.is_none_or(|head| tail.alignment.score > head.alignment.score) {
+                        *best = Some(tail);
+                    }
+                }
+            }
+        }
+
+        match self.call(&best) {
+            Some(hit) if hit.at_start => BarcodeCall::Assigned { sample: hit.barcode, start: Some(hit), end: None },
+            Some(hit) => BarcodeCall::Assigned { sample: hit.barcode, start: None, end: Some(hit) },
+            None => BarcodeCall::Unclassified,
+        }
+    }
+
+    /// The barcode called at one read end. The two end windows never
+    /// overlap, so one hit can't be seen at both ends.
+    fn end_call(&self, sequence: &str, quality: &str, at_start: bool) -> Option<BarcodeHit> {
+        let len = sequence.len();
+        let window = self.search_window.min(len / 2);
+        let (start, end) = if at_start { (0, window) } else { (len - window, len) };
+        self.call(&self.region_hits(sequence, quality, start, end, at_start))
+    }
+
+    /// Assigns a read to the sample whose 5' barcode is called forward at
+    /// the 5' end and whose 3' barcode is called reverse complemented at
+    /// the 3' end. With `allow_single_end` one of the two is enough when it
+    /// belongs to a single sample.
+    fn assign_dual(&self, sequence: &str, quality: &str) -> BarcodeCall {
+        let start = self.end_call(sequence, quality, true).filter(|hit| !hit.reverse_complement);
+        let end = self.end_call(sequence, quality, false).filter(|hit| hit.reverse_complement);
+        let only = |matches: &dyn Fn(&Sample) -> bool| {
+            let mut found = self.samples.iter().enumerate().filter(|(_, sample)| matches(sample));
+            match (found.next(), found.next()) {
+                (Some((index, _)), None) => Some(index),
+                _ => None,
+            }
+        };
+
+        let sample = match (start, end) {
+            (None, None) => None,
+            (Some(s), Some(e)) => match only(&|sample| sample.start == s.barcode && sample.end == e.barcode) {
+                Some(sample) => Some(sample),
+                None => return BarcodeCall::Chimeric { start: s.barcode, end: e.barcode },
+            },
+            _ if !self.allow_single_end => None,
+            (Some(s), None) => only(&|sample| sample.start == s.barcode),
+            (None, Some(e)) => only(&|sample| sample.end == e.barcode),
+        };
+        match sample {
+            Some(sample) => BarcodeCall::Assigned { sample, start, end },
+            None => BarcodeCall::Unclassified,
+        }
+    }
+
+    /// Range of the read left after cutting off the assigned barcode hits,
+    /// everything outside them and `flank` more bases on the insert side.
+    pub fn trim_range(&self, len: usize, start: Option<BarcodeHit>, end: Option<BarcodeHit>) -> (usize, usize) {
+        let trim_start = start.map_or(0, |hit| (hit.alignment.read_end + self.flank).min(len));
+        let trim_end = end.map_or(len, |hit| hit.alignment.read_start.saturating_sub(self.flank));
+        (trim_start, trim_end.max(trim_start))
+    }
+}
+
+/// Sample names become output file names, so they must be unique, usable
+/// in a path and different from the `unclassified` output. Barcodes are
+/// held to the same rules, as they name the samples by default.
+pub fn check_names<'a>(kind: &str, names: impl IntoIterator<Item = &'a str>) -> Result<(), String> {
+    let mut seen = std::collections::HashSet::new();
+    for name in names {
+        if name.is_empty() || name.contains(std::path::is_separator) {
+            return Err(format!("{} name '{}' can't be used in an output file name", kind, name));
+        }
+        if name == "unclassified" {
+            return Err(format!("{} name 'unclassified' is reserved for unclassified reads", kind));
+        }
+        if !seen.insert(name) {
+            return Err(format!("{} name '{}' is used more than once", kind, name));
+        }
+    }
+    Ok(())
+}
+
+/// Output path for one sample: the sample name is inserted before the
+/// FASTQ extension of `output_file`, e.g. `out.fq.gz` -> `out.BC01.fq.gz`.
+pub fn sample_output_path(output_file: &str, sample: &str) -> String {
+    for extension in [".fastq.gz", ".fq.gz", ".fastq", ".fq", ".gz"] {
+        if let Some(stem) = output_file.strip_suffix(extension) {
+            return format!("{}.{}{}", stem, sample, extension);
+        }
+    }
+    format!("{}.{}.fq.gz", output_file, sample)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::align::Scoring;
+
+    const BARCODES: [(&str, &str); 3] = [
+        ("BC01", "AAGAAAGTTGTCGGTGTCTTTGTG"),
+        ("BC02", "TCGATTCCGTTTGTAGTCGTCTGT"),
+        ("BC03", "GAGTCTTGTGTCCCAGTTACCAGG"),
+    ];
+    const INSERT: &str = "GATTACAGATTACACCATGGTTAACCGGTTAACCATGGATCCATGCATGCAAGCTTGCATGC";
+
+    fn config(dual: bool, allow_single_end: bool, pairs: &[(&str, usize, usize)]) -> DemuxConfig {
+        let barcodes: Vec<Barcode> = BARCODES
+            .iter()
+            .map(|(name, sequence)| Barcode { name: name.to_string(), sequence: sequence.to_string() })
+            .collect();
+        let samples = if pairs.is_empty() {
+            single_samples(&barcodes)
+        } else {
+            pairs.iter().map(|&(name, start, end)| Sample { name: name.to_string(), start, end }).collect()
+        };
+        DemuxConfig {
+            barcodes,
+            samples,
+            alignment: AdapterConfig {
+                scoring: Scoring::default(),
+                min_match: 0,
+                max_mismatches: None,
+                max_indels: None,
+                max_error_rate: Some(0.15),
+                min_score: 0,
+                min_identity: 0.0,
+                search_window: None,
+                internal_scan: false,
+                min_end_overlap: None,
+                end_error_rate: 0.1,
+                umi_pattern: None,
+                orient_reads: false,
+                polyx_bases: Vec::new(),
+                polyx_min_length: 10,
+                polyx_error_rate: 0.1,
+            },
+            search_window: 60,
+            min_score_gap: 6,
+            require_both_ends: dual,
+            allow_single_end,
+            flank: 0,
+        }
+    }
+
+    fn read(start: Option<usize>, end: Option<usize>) -> String {
+        let start = start.map_or(String::new(), |index| BARCODES[index].1.to_string());
+        let end = end.map_or(String::new(), |index| reverse_complement(BARCODES[index].1));
+        format!("TTTT{}{}{}{}{}TTTT", start, INSERT, INSERT, INSERT, end)
+    }
+
+    fn sample(call: BarcodeCall) -> Option<usize> {
+        match call {
+            BarcodeCall::Assigned { sample, .. } => Some(sample),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn single_mode_takes_the_best_hit_at_either_end() {
+        let demux = config(false, false, &[]);
+        assert_eq!(sample(demux.assign(&read(Some(1), None), "")), Some(1));
+        assert_eq!(sample(demux.assign(&read(None, Some(2)), "")), Some(2));
+        // Conflicting ends are not chimeras here, just too close to call.
+        assert!(matches!(demux.assign(&read(Some(0), Some(2)), ""), BarcodeCall::Unclassified));
+        assert!(matches!(demux.assign(&read(None, None), ""), BarcodeCall::Unclassified));
+    }
+
+    #[test]
+    fn dual_mode_needs_both_ends() {
+        let demux = config(true, false, &[]);
+        assert_eq!(sample(demux.assign(&read(Some(1), Some(1)), "")), Some(1));
+        assert!(matches!(demux.assign(&read(Some(1), None), ""), BarcodeCall::Unclassified));
+        assert!(matches!(demux.assign(&read(Some(0), Some(2)), ""), BarcodeCall::Chimeric { start: 0, end: 2 }));
+
+        let demux = config(true, true, &[]);
+        assert_eq!(sample(demux.assign(&read(Some(1), None), "")), Some(1));
+    }
+
+    #[test]
+    fn dual_mode_accepts_asymmetric_pairs() {
+        let demux = config(true, false, &[("S1", 0, 1), ("S2", 1, 0)]);
+        assert_eq!(sample(demux.assign(&read(Some(0), Some(1)), "")), Some(0));
+        assert_eq!(sample(demux.assign(&read(Some(1), Some(0)), "")), Some(1));
+        assert!(matches!(demux.assign(&read(Some(0), Some(0)), ""), BarcodeCall::Chimeric { .. }));
+    }
+
+    #[test]
+    fn single_end_needs_a_unique_sample() {
+        let demux = config(true, true, &[("S1", 0, 1), ("S2", 0, 2), ("S3", 2, 2)]);
+        assert!(matches!(demux.assign(&read(Some(0), None), ""), BarcodeCall::Unclassified));
+        assert_eq!(sample(demux.assign(&read(None, Some(1)), "")), Some(0));
+    }
+
+    #[test]
+    fn trim_range_cuts_barcodes_and_flank() {
+        let mut demux = config(true, false, &[]);
+        demux.flank = 4;
+        let sequence = read(Some(1), Some(1));
+        let BarcodeCall::Assigned { start, end, .. } = demux.assign(&sequence, "") else {
+            panic!("read not assigned");
+        };
+        let (trim_start, trim_end) = demux.trim_range(sequence.len(), start, end);
+        assert_eq!((trim_start, trim_end), (4 + 24 + 4, sequence.len() - 4 - 24 - 4));
+    }
+
+    #[test]
+    fn barcode_names_must_be_unique() {
+        assert!(check_names("barcode", ["BC01", "BC02"]).is_ok());
+        assert!(check_names("barcode", ["BC01", "BC02", "BC01"]).is_err());
+    }
+
+    #[test]
+    fn reserved_and_path_names_are_rejected() {
+        assert!(check_names("barcode", ["unclassified"]).is_err());
+        assert!(check_names("barcode", [""]).is_err());
+        assert!(check_names("sample", ["run/BC01"]).is_err());
+    }
+
+    #[test]
+    fn sample_path_keeps_extension() {
+        assert_eq!(sample_output_path("out.fq.gz", "BC01"), "out.BC01.fq.gz");
+        assert_eq!(sample_output_path("out.fastq", "BC01"), "out.BC01.fastq");
+        assert_eq!(sample_output_path("out", "BC01"), "out.BC01.fq.gz");
+    }
+}
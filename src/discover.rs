@@ -0,0 +1,320 @@
+//! `discover-adapters`: guess adapter sequences from k-mers that are
+//! over-represented in the first and last bases of reads.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Error as IoError, Write};
+
+use crate::{open_input, reverse_complement};
+
+pub struct DiscoverOptions<'a> {
+    pub input_file: &'a str,
+    pub output_file: Option<&'a str>,
+    pub num_reads: usize,
+    pub end_length: usize,
+    pub kmer_size: usize,
+    pub min_frequency: f64,
+    pub top: usize,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ReadEnd {
+    Five,
+    Three,
+}
+
+impl ReadEnd {
+    fn label(self) -> &'static str {
+        match self {
+            ReadEnd::Five => "5'",
+            ReadEnd::Three => "3'",
+        }
+    }
+}
+
+struct Candidate {
+    sequence: String,
+    end: ReadEnd,
+    reads: usize,
+}
+
+/// Reads containing each k-mer within the first and last bases of reads.
+#[derive(Default)]
+struct EndCounts {
+    five: HashMap<u64, usize>,
+    three: HashMap<u64, usize>,
+    sampled: usize,
+}
+
+impl EndCounts {
+    fn add(&mut self, seq: &[u8], end_length: usize, k: usize) {
+        let window = end_length.min(seq.len());
+        for kmer in window_kmers(&seq[..window], k) {
+            *self.five.entry(kmer).or_insert(0) += 1;
+        }
+        for kmer in window_kmers(&seq[seq.len() - window..], k) {
+            *self.three.entry(kmer).or_insert(0) += 1;
+        }
+        self.sampled += 1;
+    }
+
+    /// The `top` assembled candidates from both ends, most reads first and
+    /// longer sequences breaking ties.
+    fn candidates(&self, k: usize, min_frequency: f64, end_length: usize, top: usize) -> Vec<Candidate> {
+        let min_reads = ((min_frequency * self.sampled as f64).ceil() as usize).max(2);
+        let mut candidates: Vec<Candidate> = Vec::new();
+        for (end, counts) in [(ReadEnd::Five, &self.five), (ReadEnd::Three, &self.three)] {
+            for (sequence, reads) in assemble(counts, k, min_reads, end_length) {
+                candidates.push(Candidate { sequence, end, reads });
+            }
+        }
+        candidates.sort_by(|a, b| b.reads.cmp(&a.reads).then(b.sequence.len().cmp(&a.sequence.len())));
+        candidates.truncate(top);
+        candidates
+    }
+}
+
+fn encode_base(base: u8) -> Option<u64> {
+    match base.to_ascii_uppercase() {
+        b'A' => Some(0),
+        b'C' => Some(1),
+        b'G' => Some(2),
+        b'T' | b'U' => Some(3),
+        _ => None,
+    }
+}
+
+fn decode(kmer: u64, k: usize) -> Vec<u8> {
+    (0..k)
+        .rev()
+        .map(|i| b"ACGT"[((kmer >> (2 * i)) & 3) as usize])
+        .collect()
+}
+
+/// Distinct 2-bit encoded k-mers of `window`, skipping any containing
+/// non-ACGT bases.
+fn window_kmers(window: &[u8], k: usize) -> Vec<u64> {
+    let mask = if k == 32 { u64::MAX } else { (1u64 << (2 * k)) - 1 };
+    let mut kmers = Vec::new();
+    let mut kmer = 0u64;
+    let mut valid = 0;
+    for &base in window {
+        match encode_base(base) {
+            Some(code) => {
+                kmer = ((kmer << 2) | code) & mask;
+                valid += 1;
+                if valid >= k {
+                    kmers.push(kmer);
+                }
+            }
+            None => valid = 0,
+        }
+    }
+    kmers.sort_unstable();
+    kmers.dedup();
+    kmers
+}
+
+/// Homopolymer and dinucleotide k-mers (poly-A tails, simple repeats) are
+/// common at read ends but are not adapters.
+fn is_informative(kmer: u64, k: usize) -> bool {
+    let mut seen = [false; 4];
+    for i in 0..k {
+        seen[((kmer >> (2 * i)) & 3) as usize] = true;
+    }
+    seen.iter().filter(|&&s| s).count() >= 3
+}
+
+/// Greedily extends the most frequent k-mers into longer sequences, walking
+/// to the most frequent unused neighbour on either side while it stays
+/// above `min_reads`. Contigs that share a 2k/3-mer with a more frequent
+/// one are branches off its ends (adapter followed by insert) and dropped.
+fn assemble(counts: &HashMap<u64, usize>, k: usize, min_reads: usize, max_len: usize) -> Vec<(String, usize)> {
+    let mask = if k == 32 { u64::MAX } else { (1u64 << (2 * k)) - 1 };
+    let count_of = |kmer: u64| counts.get(&kmer).copied().unwrap_or(0);
+
+    let mut seeds: Vec<(u64, usize)> = counts
+        .iter()
+        .filter(|&(&kmer, &count)| count >= min_reads && is_informative(kmer, k))
+        .map(|(&kmer, &count)| (kmer, count))
+        .collect();
+    seeds.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    let mut used: HashSet<u64> = HashSet::new();
+    let mut assembled_overlaps: HashSet<u64> = HashSet::new();
+    let mut contigs = Vec::new();
+    for (seed, count) in seeds {
+        if !used.insert(seed) {
+            continue;
+        }
+        let mut contig = decode(seed, k);
+
+        let mut kmer = seed;
+        while contig.len() < max_len {
+            let next = (0..4u64)
+                .map(|code| ((kmer << 2) | code) & mask)
+                .filter(|next| !used.contains(next) && count_of(*next) >= min_reads)
+                .max_by_key(|&next| (count_of(next), std::cmp::Reverse(next)));
+            let Some(next) = next else { break };
+            used.insert(next);
+            contig.push(b"ACGT"[(next & 3) as usize]);
+            kmer = next;
+        }
+
+        let mut kmer = seed;
+        while contig.len() < max_len {
+            let prev = (0..4u64)
+                .map(|code| (kmer >> 2) | (code << (2 * (k - 1))))
+                .filter(|prev| !used.contains(prev) && count_of(*prev) >= min_reads)
+                .max_by_key(|&prev| (count_of(prev), std::cmp::Reverse(prev)));
+            let Some(prev) = prev else { break };
+            used.insert(prev);
+            contig.insert(0, b"ACGT"[(prev >> (2 * (k - 1))) as usize]);
+            kmer = prev;
+        }
+
+        let overlaps = window_kmers(&contig, (2 * k / 3).max(4));
+        if overlaps.iter().any(|kmer| assembled_overlaps.contains(kmer)) {
+            continue;
+        }
+        assembled_overlaps.extend(overlaps);
+        contigs.push((String::from_utf8(contig).unwrap(), count));
+    }
+    contigs
+}
+
+/// Ligation adapters show up forward at one end and reverse complemented at
+/// the other; the second copy points at the first, higher ranked one.
+fn rc_of(candidates: &[Candidate], rank: usize, k: usize) -> Option<usize> {
+    let candidate = &candidates[rank];
+    let rev_comp = window_kmers(reverse_complement(&candidate.sequence).as_bytes(), k);
+    candidates[..rank].iter().position(|other| {
+        other.end != candidate.end
+            && window_kmers(other.sequence.as_bytes(), k)
+                .iter()
+                .any(|kmer| rev_comp.binary_search(kmer).is_ok())
+    })
+}
+
+pub fn discover_adapters(options: &DiscoverOptions) -> Result<(), IoError> {
+    let k = options.kmer_size;
+    let reader = open_input(std::path::Path::new(options.input_file))?;
+
+    let mut counts = EndCounts::default();
+    let mut lines = reader.lines();
+    while counts.sampled < options.num_reads {
+        let Some(_header) = lines.next().transpose()? else { break };
+        let sequence = lines.next().transpose()?.unwrap_or_default();
+        lines.next().transpose()?;
+        lines.next().transpose()?;
+        counts.add(sequence.as_bytes(), options.end_length, k);
+    }
+
+    let sampled = counts.sampled;
+    let candidates = counts.candidates(k, options.min_frequency, options.end_length, options.top);
+
+    let mut writer: Box<dyn Write> = match options.output_file {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(std::io::stdout())),
+    };
+    for (rank, candidate) in candidates.iter().enumerate() {
+        write!(
+            writer,
+            ">adapter{} end={} reads={} frequency={:.4}",
+            rank + 1,
+            candidate.end.label(),
+            candidate.reads,
+            candidate.reads as f64 / sampled as f64
+        )?;
+        if let Some(other) = rc_of(&candidates, rank, k) {
+            write!(writer, " rc_of=adapter{}", other + 1)?;
+        }
+        writeln!(writer, "\n{}", candidate.sequence)?;
+    }
+    writer.flush()?;
+
+    eprintln!("Sampled reads: {}", sampled);
+    eprintln!("Candidate adapters: {}", candidates.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADAPTER: &str = "AGATCGGAAGAGCACACGTCTGAACTCC";
+
+    /// Pseudo-random bases, different for every `seed`.
+    fn insert(seed: u32, len: usize) -> String {
+        let mut state = seed.wrapping_mul(2654435761).wrapping_add(1);
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                b"ACGT"[(state >> 16) as usize % 4] as char
+            })
+            .collect()
+    }
+
+    fn encode(kmer: &str) -> u64 {
+        kmer.bytes().fold(0, |acc, base| (acc << 2) | encode_base(base).unwrap())
+    }
+
+    #[test]
+    fn window_kmers_are_distinct_and_skip_ambiguous_bases() {
+        assert_eq!(window_kmers(b"ACGACGA", 3), [encode("ACG"), encode("CGA"), encode("GAC")]);
+        // Lower case and U count as bases, N breaks the k-mer.
+        assert_eq!(window_kmers(b"acuNGTA", 3), [encode("ACT"), encode("GTA")]);
+        assert_eq!(window_kmers(b"ACNGT", 3), []);
+        assert_eq!(decode(encode("GATTACA"), 7), b"GATTACA");
+    }
+
+    #[test]
+    fn repeats_are_not_informative() {
+        assert!(!is_informative(encode("AAAAAAAA"), 8));
+        assert!(!is_informative(encode("ACACACAC"), 8));
+        assert!(is_informative(encode("ACGACGAC"), 8));
+    }
+
+    #[test]
+    fn assemble_extends_seeds_into_the_adapter() {
+        let k = 8;
+        let mut counts = HashMap::new();
+        for kmer in window_kmers(ADAPTER.as_bytes(), k) {
+            counts.insert(kmer, 10);
+        }
+        // A poly-A k-mer is frequent but never seeds a contig.
+        counts.insert(encode("AAAAAAAA"), 50);
+        // Below min_reads, so not assembled.
+        counts.insert(encode("TTGCCAGT"), 1);
+        assert_eq!(assemble(&counts, k, 2, 64), [(ADAPTER.to_string(), 10)]);
+        // max_len stops extension.
+        assert_eq!(assemble(&counts, k, 2, 12)[0].0.len(), 12);
+    }
+
+    #[test]
+    fn planted_adapters_rank_first_and_pair_up() {
+        let k = 12;
+        let mut counts = EndCounts::default();
+        let rc = reverse_complement(ADAPTER);
+        for read in 0..200u32 {
+            let mut sequence = insert(read, 150);
+            if read % 10 < 6 {
+                sequence.push_str(ADAPTER);
+            }
+            if read % 10 < 3 {
+                sequence.insert_str(0, &rc);
+            }
+            counts.add(sequence.as_bytes(), 40, k);
+        }
+        assert_eq!(counts.sampled, 200);
+
+        let candidates = counts.candidates(k, 0.05, 40, 5);
+        assert_eq!(candidates.len(), 2, "{:?}", candidates.iter().map(|c| &c.sequence).collect::<Vec<_>>());
+        assert!(candidates[0].end == ReadEnd::Three && candidates[0].sequence.contains(ADAPTER));
+        assert_eq!(candidates[0].reads, 120);
+        assert!(candidates[1].end == ReadEnd::Five && candidates[1].sequence.contains(&rc));
+        assert_eq!(candidates[1].reads, 60);
+        assert_eq!(rc_of(&candidates, 0, k), None);
+        assert_eq!(rc_of(&candidates, 1, k), Some(0));
+    }
+}
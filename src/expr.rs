@@ -0,0 +1,519 @@
+//! `--filter-expr`: a boolean expression over per-record values, compiled
+//! once at startup, e.g. `len >= 1000 && q >= 7 && (gc < 0.65 || field.ch in [1..200])`.
+//!
+//! Comparisons are `== != < <= > >=` and `in [...]` with values and
+//! inclusive `low..high` ranges, combined with `&& || !` and parentheses.
+//! Besides the built-in variables, names are `--metadata` columns or
+//! `field.key` for a `key=value` field of the read header; any other name
+//! is an error. A comparison with a value the read lacks is false.
+
+use std::cmp::Ordering;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Variable {
+    /// `len` or `length`.
+    Length,
+    /// `q`: the score at the end of the read header.
+    HeaderQuality,
+    /// `computed_q`: the `--quality-metric` value on the Phred scale, or the
+    /// mean quality for the header and expected-errors metrics.
+    ComputedQuality,
+    /// `gc`: GC fraction of called bases.
+    Gc,
+    /// `n`: fraction of `N` bases.
+    NFraction,
+    /// `adapters`: adapter hits in the read, including trimmed partial ones.
+    AdapterHits,
+    /// `field.key`: a `key=value` header field.
+    Field(String),
+    /// A metadata table column.
+    Column(String),
+}
+
+impl Variable {
+    fn from_name(name: &str, columns: &[String]) -> Option<Variable> {
+        let variable = match name {
+            "len" | "length" => Variable::Length,
+            "q" => Variable::HeaderQuality,
+            "computed_q" => Variable::ComputedQuality,
+            "gc" => Variable::Gc,
+            "n" => Variable::NFraction,
+            "adapters" => Variable::AdapterHits,
+            _ => match name.strip_prefix("field.") {
+                Some(key) if !key.is_empty() => Variable::Field(key.to_string()),
+                _ if columns.iter().any(|column| column == name) => Variable::Column(name.to_string()),
+                _ => return None,
+            },
+        };
+        Some(variable)
+    }
+
+    /// Built-in variables are numbers; fields and columns are text that may
+    /// hold a number.
+    fn is_number(&self) -> bool {
+        !matches!(self, Variable::Field(_) | Variable::Column(_))
+    }
+}
+
+impl fmt::Display for Variable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Variable::Length => "len",
+            Variable::HeaderQuality => "q",
+            Variable::ComputedQuality => "computed_q",
+            Variable::Gc => "gc",
+            Variable::NFraction => "n",
+            Variable::AdapterHits => "adapters",
+            Variable::Field(key) => return write!(f, "field.{}", key),
+            Variable::Column(name) => name,
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Number(f64),
+    Text(String),
+}
+
+impl Value {
+    /// Header fields are text but compare as numbers where they parse.
+    fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Number(number) => Some(*number),
+            Value::Text(text) => text.parse().ok(),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Number(number) => write!(f, "{}", number),
+            Value::Text(text) => write!(f, "\"{}\"", text),
+        }
+    }
+}
+
+/// Text compares as text, anything else as numbers; `None` when a side
+/// isn't a number.
+pub fn compare(left: &Value, right: &Value) -> Option<Ordering> {
+    match (left, right) {
+        (Value::Text(left), Value::Text(right)) => Some(left.cmp(right)),
+        _ => left.as_number()?.partial_cmp(&right.as_number()?),
+    }
+}
+
+#[derive(Debug)]
+enum Operand {
+    Variable(Variable),
+    Literal(Value),
+}
+
+impl Operand {
+    fn value(&self, lookup: &dyn Fn(&Variable) -> Option<Value>) -> Option<Value> {
+        match self {
+            Operand::Variable(variable) => lookup(variable),
+            Operand::Literal(value) => Some(value.clone()),
+        }
+    }
+
+    /// Built-in variables are numbers, so comparing them with text is an
+    /// error rather than always false.
+    fn check_comparable(&self, value: &Value) -> Result<(), String> {
+        match (self, value) {
+            (Operand::Variable(variable), Value::Text(_)) if variable.is_number() => {
+                Err(format!("'{}' is a number and can't be compared with {}", variable, value))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CompareOp {
+    pub fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            CompareOp::Eq => ordering == Ordering::Equal,
+            CompareOp::Ne => ordering != Ordering::Equal,
+            CompareOp::Lt => ordering == Ordering::Less,
+            CompareOp::Le => ordering != Ordering::Greater,
+            CompareOp::Gt => ordering == Ordering::Greater,
+            CompareOp::Ge => ordering != Ordering::Less,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Item {
+    Value(Value),
+    /// Inclusive numeric range.
+    Range(f64, f64),
+}
+
+impl Item {
+    fn contains(&self, value: &Value) -> bool {
+        match self {
+            Item::Value(item) => compare(value, item) == Some(Ordering::Equal),
+            Item::Range(low, high) => value.as_number().is_some_and(|number| *low <= number && number <= *high),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Node {
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Not(Box<Node>),
+    Compare(Operand, CompareOp, Operand),
+    In(Operand, Vec<Item>),
+}
+
+impl Node {
+    fn eval(&self, lookup: &dyn Fn(&Variable) -> Option<Value>) -> bool {
+        match self {
+            Node::And(left, right) => left.eval(lookup) && right.eval(lookup),
+            Node::Or(left, right) => left.eval(lookup) || right.eval(lookup),
+            Node::Not(node) => !node.eval(lookup),
+            Node::Compare(left, op, right) => match (left.value(lookup), right.value(lookup)) {
+                (Some(left), Some(right)) => compare(&left, &right).is_some_and(|ordering| op.holds(ordering)),
+                _ => false,
+            },
+            Node::In(operand, items) => operand.value(lookup).is_some_and(|value| items.iter().any(|item| item.contains(&value))),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Text(String),
+    Name(String),
+    Op(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Number(number) => write!(f, "{}", number),
+            Token::Text(text) => write!(f, "\"{}\"", text),
+            Token::Name(name) => write!(f, "'{}'", name),
+            Token::Op(op) => write!(f, "'{}'", op),
+        }
+    }
+}
+
+// Longer operators first so that `<=` isn't read as `<`.
+const OPERATORS: [&str; 16] = ["&&", "||", "==", "!=", "<=", ">=", "..", "<", ">", "!", "(", ")", "[", "]", ",", "-"];
+
+/// Tokens with their byte offsets in `source`.
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, String> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        let c = bytes[i];
+        if c.is_ascii_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() {
+            while i < bytes.len() && bytes[i].is_ascii_digit() {
+                i += 1;
+            }
+            // A '.' only starts a fraction when a digit follows, so `1..200` is a range.
+            if bytes.get(i) == Some(&b'.') && bytes.get(i + 1).is_some_and(u8::is_ascii_digit) {
+                i += 1;
+                while i < bytes.len() && bytes[i].is_ascii_digit() {
+                    i += 1;
+                }
+            }
+            tokens.push((start, Token::Number(source[start..i].parse().unwrap())));
+        } else if c.is_ascii_alphabetic() || c == b'_' {
+            // Dots join name parts, as in `field.ch`.
+            let is_name_start = |byte: Option<&u8>| byte.is_some_and(|&b| b.is_ascii_alphabetic() || b == b'_');
+            while i < bytes.len()
+                && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_' || (bytes[i] == b'.' && is_name_start(bytes.get(i + 1))))
+            {
+                i += 1;
+            }
+            tokens.push((start, Token::Name(source[start..i].to_string())));
+        } else if c == b'"' || c == b'\'' {
+            let Some(length) = source[i + 1..].find(c as char) else {
+                return Err(format!("unterminated string at position {}", start + 1));
+            };
+            tokens.push((start, Token::Text(source[i + 1..i + 1 + length].to_string())));
+            i += length + 2;
+        } else {
+            let Some(op) = OPERATORS.iter().find(|op| source[i..].starts_with(**op)) else {
+                let c = source[i..].chars().next().unwrap();
+                return Err(format!("unexpected '{}' at position {}", c, start + 1));
+            };
+            tokens.push((start, Token::Op(op)));
+            i += op.len();
+        }
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent over the tokens, loosest binding first: `||`, `&&`,
+/// `!`, then comparisons.
+struct Parser<'a> {
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+    /// Metadata columns, usable as names.
+    columns: &'a [String],
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(_, token)| token)
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        let found = self.peek() == Some(token);
+        if found {
+            self.next += 1;
+        }
+        found
+    }
+
+    fn error(&self, expected: &str) -> String {
+        match self.tokens.get(self.next) {
+            Some((position, token)) => format!("expected {} at position {}, found {}", expected, position + 1, token),
+            None => format!("expected {} at end of expression", expected),
+        }
+    }
+
+    fn expect(&mut self, op: &'static str, expected: &str) -> Result<(), String> {
+        if self.eat(&Token::Op(op)) {
+            Ok(())
+        } else {
+            Err(self.error(expected))
+        }
+    }
+
+    fn or(&mut self) -> Result<Node, String> {
+        let mut node = self.and()?;
+        while self.eat(&Token::Op("||")) {
+            node = Node::Or(Box::new(node), Box::new(self.and()?));
+        }
+        Ok(node)
+    }
+
+    fn and(&mut self) -> Result<Node, String> {
+        let mut node = self.not()?;
+        while self.eat(&Token::Op("&&")) {
+            node = Node::And(Box::new(node), Box::new(self.not()?));
+        }
+        Ok(node)
+    }
+
+    fn not(&mut self) -> Result<Node, String> {
+        if self.eat(&Token::Op("!")) {
+            return Ok(Node::Not(Box::new(self.not()?)));
+        }
+        if self.peek() == Some(&Token::Op("(")) {
+            // Either a group or a parenthesized operand as in `(len) >= 5`:
+            // try the comparison first, and report whichever parse got
+            // further if neither works.
+            let start = self.next;
+            let comparison = self.comparison();
+            if comparison.is_ok() {
+                return comparison;
+            }
+            let comparison_end = self.next;
+            self.next = start + 1;
+            let group = self.or().and_then(|node| self.expect(")", "')'").map(|_| node));
+            if group.is_err() && comparison_end > self.next {
+                return comparison;
+            }
+            return group;
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Node, String> {
+        let left = self.operand()?;
+        if self.eat(&Token::Name("in".to_string())) {
+            self.expect("[", "'['")?;
+            let mut items = Vec::new();
+            loop {
+                let value = self.literal()?;
+                left.check_comparable(&value)?;
+                if self.eat(&Token::Op("..")) {
+                    let high = self.literal()?;
+                    let (Value::Number(low), Value::Number(high)) = (&value, &high) else {
+                        return Err(format!("range {}..{} needs numbers", value, high));
+                    };
+                    items.push(Item::Range(*low, *high));
+                } else {
+                    items.push(Item::Value(value));
+                }
+                if !self.eat(&Token::Op(",")) {
+                    break;
+                }
+            }
+            self.expect("]", "',' or ']'")?;
+            return Ok(Node::In(left, items));
+        }
+
+        let op = match self.peek() {
+            Some(Token::Op("==")) => CompareOp::Eq,
+            Some(Token::Op("!=")) => CompareOp::Ne,
+            Some(Token::Op("<")) => CompareOp::Lt,
+            Some(Token::Op("<=")) => CompareOp::Le,
+            Some(Token::Op(">")) => CompareOp::Gt,
+            Some(Token::Op(">=")) => CompareOp::Ge,
+            _ => return Err(self.error("a comparison operator or 'in'")),
+        };
+        self.next += 1;
+        let right = self.operand()?;
+        if let Operand::Literal(value) = &right {
+            left.check_comparable(value)?;
+        }
+        if let Operand::Literal(value) = &left {
+            right.check_comparable(value)?;
+        }
+        Ok(Node::Compare(left, op, right))
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        if self.eat(&Token::Op("(")) {
+            let operand = self.operand()?;
+            self.expect(")", "')'")?;
+            return Ok(operand);
+        }
+        if let Some((position, Token::Name(name))) = self.tokens.get(self.next) {
+            let Some(variable) = Variable::from_name(name, self.columns) else {
+                return Err(format!(
+                    "unknown name '{}' at position {}; header fields are written field.{}",
+                    name, position + 1, name
+                ));
+            };
+            self.next += 1;
+            return Ok(Operand::Variable(variable));
+        }
+        Ok(Operand::Literal(self.literal()?))
+    }
+
+    fn literal(&mut self) -> Result<Value, String> {
+        let negative = self.eat(&Token::Op("-"));
+        let value = match self.peek() {
+            Some(Token::Number(number)) => Value::Number(if negative { -number } else { *number }),
+            Some(Token::Text(text)) if !negative => Value::Text(text.clone()),
+            _ => return Err(self.error(if negative { "a number" } else { "a value" })),
+        };
+        self.next += 1;
+        Ok(value)
+    }
+}
+
+#[derive(Debug)]
+pub struct FilterExpr {
+    root: Node,
+}
+
+impl FilterExpr {
+    /// Compiles `source`, which may name the metadata `columns`.
+    pub fn parse(source: &str, columns: &[String]) -> Result<Self, String> {
+        let mut parser = Parser { tokens: tokenize(source)?, next: 0, columns };
+        let root = parser.or()?;
+        if parser.next < parser.tokens.len() {
+            return Err(parser.error("'&&', '||' or the end of the expression"));
+        }
+        Ok(FilterExpr { root })
+    }
+
+    /// Evaluates the expression with variable values from `lookup`, which
+    /// returns `None` for values a record doesn't have.
+    pub fn matches(&self, lookup: &dyn Fn(&Variable) -> Option<Value>) -> bool {
+        self.root.eval(lookup)
+    }
+}
+
+/// Value of a `key=value` field in a read header.
+pub fn header_field<'a>(header: &'a str, key: &str) -> Option<&'a str> {
+    header.split_whitespace().skip(1).find_map(|field| field.strip_prefix(key)?.strip_prefix('='))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Result<FilterExpr, String> {
+        FilterExpr::parse(source, &["taxid".to_string(), "sample".to_string()])
+    }
+
+    /// Evaluates `source` for a read of length 500, Q 9, field ch=12 and
+    /// metadata taxid 9606.
+    fn holds(source: &str) -> bool {
+        parse(source).unwrap().matches(&|variable| match variable {
+            Variable::Length => Some(Value::Number(500.0)),
+            Variable::HeaderQuality => Some(Value::Number(9.0)),
+            Variable::Field(key) if key == "ch" => Some(Value::Text("12".to_string())),
+            Variable::Column(name) if name == "taxid" => Some(Value::Text("9606".to_string())),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert!(holds("len > 1000 && q > 5 || q > 8"));
+        assert!(!holds("len > 1000 && (q > 5 || q > 8)"));
+        assert!(holds("q > 8 || len > 1000 && q > 100"));
+        assert!(!holds("!len > 100"));
+        assert!(holds("!(len > 1000) && q >= 9"));
+    }
+
+    #[test]
+    fn parenthesized_operands() {
+        assert!(holds("(len) >= 5"));
+        assert!(holds("((len)) >= 5 && 10 > (q)"));
+        assert!(holds("(len) in [1..1000]"));
+        assert!(holds("((len >= 5))"));
+        assert!(parse("(len >= 5").unwrap_err().contains("')'"));
+        assert!(parse("(len) >= ").is_err());
+    }
+
+    #[test]
+    fn unknown_names_are_errors() {
+        let error = parse("len > 5 && ch < 10").unwrap_err();
+        assert!(error.contains("unknown name 'ch' at position 12"), "{}", error);
+        assert!(error.contains("field.ch"));
+        assert!(parse("field. > 1").is_err());
+        assert!(FilterExpr::parse("taxid == 9606", &[]).is_err());
+    }
+
+    #[test]
+    fn fields_and_columns() {
+        assert!(holds("field.ch in [1..200]"));
+        assert!(holds("field.ch == 12 && field.ch == \"12\""));
+        assert!(holds("taxid == 9606"));
+        assert!(!holds("sample == \"a\"") && !holds("sample != \"a\""));
+        assert!(!holds("field.missing > 0") && !holds("field.missing <= 0"));
+    }
+
+    #[test]
+    fn text_compares_as_text_and_numbers_as_numbers() {
+        // "12" < "2" as text, 12 > 2 as a number.
+        assert!(holds("field.ch < \"2\""));
+        assert!(holds("field.ch > 2"));
+        assert!(holds("taxid in [\"562\", \"9606\"]"));
+        assert_eq!(compare(&Value::Text("abc".to_string()), &Value::Number(1.0)), None);
+        let error = parse("len == \"long\"").unwrap_err();
+        assert!(error.contains("'len' is a number"), "{}", error);
+        assert!(parse("q in [1, \"x\"]").is_err());
+        assert!(parse("len in [\"a\"..5]").is_err());
+    }
+}
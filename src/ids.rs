@@ -0,0 +1,108 @@
+//! Read selection by ID with `--include-ids`, `--exclude-ids` and
+//! `--id-regex`. A read split into `_partN` records by an earlier run is
+//! matched by its own ID and by the IDs of the reads it came from.
+
+use std::collections::HashSet;
+use std::io::BufRead;
+use std::path::Path;
+
+use regex::Regex;
+
+use crate::open_input;
+
+pub struct IdFilter {
+    pub include: Option<HashSet<Box<str>>>,
+    pub exclude: Option<HashSet<Box<str>>>,
+    pub regex: Option<Regex>,
+}
+
+/// Reads one ID per line, taking the first word and dropping a leading `@`
+/// so FASTQ headers can be used as-is. Blank lines and lines starting with
+/// `#` are skipped.
+pub fn load_ids(path: &Path) -> Result<HashSet<Box<str>>, String> {
+    let mut ids = HashSet::new();
+    for line in open_input(path).map_err(|e| e.to_string())?.lines() {
+        let line = line.map_err(|e| e.to_string())?;
+        let Some(id) = line.split_whitespace().next() else { continue };
+        if id.starts_with('#') {
+            continue;
+        }
+        ids.insert(id.trim_start_matches('@').into());
+    }
+    Ok(ids)
+}
+
+/// Read ID of a header: its first word without the `@`.
+pub fn read_id(header: &str) -> &str {
+    let id = header.split_whitespace().next().unwrap_or("");
+    id.strip_prefix('@').unwrap_or(id)
+}
+
+/// The ID of the read a `_partN` record was split from.
+fn parent_id(id: &str) -> Option<&str> {
+    let (parent, part) = id.rsplit_once("_part")?;
+    (!part.is_empty() && part.bytes().all(|b| b.is_ascii_digit())).then_some(parent)
+}
+
+/// `id` followed by the IDs it was split from, e.g. `r1_part2_part1`,
+/// `r1_part2`, `r1`.
+pub fn lineage(id: &str) -> impl Iterator<Item = &str> {
+    std::iter::successors(Some(id), |id| parent_id(id))
+}
+
+impl IdFilter {
+    pub fn selects(&self, header: &str) -> bool {
+        let id = read_id(header);
+        let listed = |ids: &HashSet<Box<str>>| lineage(id).any(|id| ids.contains(id));
+        self.include.as_ref().is_none_or(listed)
+            && !self.exclude.as_ref().is_some_and(listed)
+            && self.regex.as_ref().is_none_or(|regex| lineage(id).any(|id| regex.is_match(id)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(list: &[&str]) -> Option<HashSet<Box<str>>> {
+        Some(list.iter().map(|&id| id.into()).collect())
+    }
+
+    #[test]
+    fn parent_needs_a_numbered_part() {
+        assert_eq!(parent_id("r1_part2"), Some("r1"));
+        assert_eq!(parent_id("r1_part12_part1"), Some("r1_part12"));
+        assert_eq!(parent_id("r1_part"), None);
+        assert_eq!(parent_id("r1_partA"), None);
+        assert_eq!(parent_id("r1"), None);
+    }
+
+    #[test]
+    fn lineage_walks_back_to_the_read() {
+        assert_eq!(lineage("r1_part2_part1").collect::<Vec<_>>(), ["r1_part2_part1", "r1_part2", "r1"]);
+        assert_eq!(lineage("my_part_read").collect::<Vec<_>>(), ["my_part_read"]);
+    }
+
+    #[test]
+    fn read_id_is_the_first_word() {
+        assert_eq!(read_id("@r1 ch=5 qs:f:9.5"), "r1");
+        assert_eq!(read_id("r1_part2"), "r1_part2");
+        assert_eq!(read_id(""), "");
+    }
+
+    #[test]
+    fn parts_are_selected_by_their_reads() {
+        let filter = IdFilter { include: ids(&["r1", "r2_part1"]), exclude: ids(&["r1_part2"]), regex: None };
+        assert!(filter.selects("@r1 ch=5"));
+        assert!(filter.selects("@r1_part1"));
+        assert!(!filter.selects("@r1_part2"));
+        assert!(!filter.selects("@r1_part2_part1"));
+        assert!(filter.selects("@r2_part1_part2"));
+        assert!(!filter.selects("@r2_part2"));
+        assert!(!filter.selects("@r3"));
+
+        let filter = IdFilter { include: None, exclude: None, regex: Some(Regex::new("^r[12]$").unwrap()) };
+        assert!(filter.selects("@r2_part1"));
+        assert!(!filter.selects("@r3_part1"));
+    }
+}
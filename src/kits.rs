@@ -0,0 +1,175 @@
+//! Kit presets: adapter, barcode and primer sets with recommended alignment
+//! thresholds, either built in (`kits.toml`) or loaded with `--kit-file`.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::read_fasta;
+
+const BUILTIN_KITS: &str = include_str!("kits.toml");
+
+/// Recommended thresholds; options given on the command line win.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KitThresholds {
+    pub min_adapter_match: Option<usize>,
+    pub adapter_error_rate: Option<f64>,
+    pub adapter_search_window: Option<usize>,
+    pub min_end_overlap: Option<usize>,
+    pub min_adapter_score: Option<i32>,
+    pub min_adapter_identity: Option<f64>,
+    pub match_score: Option<i32>,
+    pub mismatch_penalty: Option<i32>,
+    pub gap_open: Option<i32>,
+    pub gap_extend: Option<i32>,
+}
+
+/// A forward/reverse primer pair for amplicon mode, both written 5' to 3'.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KitPrimerPair {
+    pub forward: String,
+    pub reverse: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Kit {
+    #[serde(skip)]
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub adapters: BTreeMap<String, String>,
+    #[serde(default)]
+    pub barcodes: BTreeMap<String, String>,
+    #[serde(default)]
+    pub primers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub amplicons: BTreeMap<String, KitPrimerPair>,
+    #[serde(default)]
+    pub thresholds: KitThresholds,
+}
+
+fn parse_toml(text: &str) -> Result<Vec<Kit>, String> {
+    let kits: BTreeMap<String, Kit> = toml::from_str(text).map_err(|e| e.to_string())?;
+    Ok(kits
+        .into_iter()
+        .map(|(name, mut kit)| {
+            kit.name = name;
+            kit
+        })
+        .collect())
+}
+
+pub fn builtin_kits() -> Vec<Kit> {
+    parse_toml(BUILTIN_KITS).expect("built-in kits.toml is valid")
+}
+
+/// Loads kits from a `.toml` file laid out like the built-in presets, or
+/// from a FASTA file whose records are tagged `type=adapter|barcode|primer`
+/// in the header (untagged records are adapters). A FASTA file holds a
+/// single kit named after the file; amplicon primer pairs need TOML.
+pub fn load_kit_file(path: &Path) -> Result<Vec<Kit>, String> {
+    if path.extension().is_some_and(|ext| ext == "toml") {
+        let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        return parse_toml(&text);
+    }
+
+    let records = read_fasta(path).map_err(|e| e.to_string())?;
+    let name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().trim_end_matches(".fa").trim_end_matches(".fasta").to_string())
+        .unwrap_or_default();
+    let mut kit = Kit {
+        name,
+        description: format!("loaded from {}", path.display()),
+        ..Kit::default()
+    };
+    for (header, sequence) in records {
+        let mut words = header.split_whitespace();
+        let record_name = words.next().unwrap_or("").to_string();
+        let set = match words.find_map(|word| word.strip_prefix("type=")).unwrap_or("adapter") {
+            "adapter" => &mut kit.adapters,
+            "barcode" => &mut kit.barcodes,
+            "primer" => &mut kit.primers,
+            other => return Err(format!("unknown record type '{}' for {}", other, record_name)),
+        };
+        set.insert(record_name, sequence);
+    }
+    Ok(vec![kit])
+}
+
+pub fn print_kits(kits: &[Kit]) {
+    for kit in kits {
+        println!("{}\t{}", kit.name, kit.description);
+        let t = &kit.thresholds;
+        let mut flags = Vec::new();
+        if let Some(value) = t.min_adapter_match {
+            flags.push(format!("--min-adapter-match {}", value));
+        }
+        if let Some(value) = t.adapter_error_rate {
+            flags.push(format!("--adapter-error-rate {}", value));
+        }
+        if let Some(value) = t.adapter_search_window {
+            flags.push(format!("--adapter-search-window {}", value));
+        }
+        if let Some(value) = t.min_end_overlap {
+            flags.push(format!("--min-end-overlap {}", value));
+        }
+        if let Some(value) = t.min_adapter_score {
+            flags.push(format!("--min-adapter-score {}", value));
+        }
+        if let Some(value) = t.min_adapter_identity {
+            flags.push(format!("--min-adapter-identity {}", value));
+        }
+        if let Some(value) = t.match_score {
+            flags.push(format!("--match-score {}", value));
+        }
+        if let Some(value) = t.mismatch_penalty {
+            flags.push(format!("--mismatch-penalty {}", value));
+        }
+        if let Some(value) = t.gap_open {
+            flags.push(format!("--gap-open {}", value));
+        }
+        if let Some(value) = t.gap_extend {
+            flags.push(format!("--gap-extend {}", value));
+        }
+        if !flags.is_empty() {
+            println!("  thresholds: {}", flags.join(" "));
+        }
+        for (kind, set) in [("adapter", &kit.adapters), ("barcode", &kit.barcodes), ("primer", &kit.primers)] {
+            for (name, sequence) in set {
+                println!("  {}\t{}\t{}", kind, name, sequence);
+            }
+        }
+        for (name, pair) in &kit.amplicons {
+            println!("  amplicon\t{}\t{}\t{}", name, pair.forward, pair.reverse);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_kits_parse() {
+        let kits = builtin_kits();
+        let kit = |name: &str| kits.iter().find(|kit| kit.name == name).unwrap();
+        assert_eq!(kit("ont-nb12").barcodes.len(), 12);
+        assert!(kit("ont-nb12").description.contains("turns on demultiplexing"));
+        assert!(kit("16s").amplicons.contains_key("16S"));
+        assert!(kit("16s").description.contains("turns on amplicon mode"));
+        assert!(kit("ont-cdna").primers.contains_key("SSP") && kit("ont-cdna").primers.contains_key("VNP"));
+        assert!(kits.iter().all(|kit| kit.adapters.contains_key("ligation")));
+    }
+
+    #[test]
+    fn unknown_threshold_is_rejected() {
+        assert!(parse_toml("[k.thresholds]\nmatch_score = 3\n").is_ok());
+        assert!(parse_toml("[k.thresholds]\nmatch = 3\n").is_err());
+    }
+}
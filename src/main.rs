@@ -1,153 +1,1008 @@
-use std::sync::{Arc, Mutex};
 use std::io::{BufReader, BufRead, Read, BufWriter, Write};
 use std::fs::File;
+use std::borrow::Cow;
 // use std::path::Path;
 // use flate2::read::GzDecoder;
 use flate2::write::GzEncoder;
 use flate2::Compression;
 use std::io::Error as IoError;
 use rayon::prelude::*;
-use num_cpus;
 
-fn filter_fastq_by_quality_and_length(
-    input_file: &str,
-    output_file: &str,
-    _num_cpus: usize,
+mod align;
+mod amplicon;
+mod cdna;
+mod complexity;
+mod dedup;
+mod demux;
+mod discover;
+mod expr;
+mod ids;
+mod kits;
+mod metadata;
+mod polyx;
+mod quality;
+mod umi;
+
+use align::{anchored_overlap, overlap_span, smith_waterman_align, Alignment, Overlap, Scoring};
+use cdna::CdnaClass;
+use demux::BarcodeCall;
+use expr::{FilterExpr, Value, Variable};
+use quality::{LengthThresholds, QualityMetric};
+
+/// A named adapter sequence from `-a` or `--adapter-file`.
+#[derive(Clone, Debug)]
+struct Adapter {
+    name: String,
+    sequence: String,
+}
+
+/// Scoring and acceptance thresholds for adapter hits.
+#[derive(Clone, Debug)]
+struct AdapterConfig {
+    scoring: Scoring,
+    min_match: usize,
+    max_mismatches: Option<usize>,
+    max_indels: Option<usize>,
+    /// Combined mismatch + indel budget as a fraction of the aligned adapter length.
+    max_error_rate: Option<f64>,
+    min_score: i32,
+    min_identity: f64,
+    /// Only search the first and last `search_window` bases of each read.
+    search_window: Option<usize>,
+    /// With a search window, fall back to scanning the whole read when
+    /// neither end has a hit.
+    internal_scan: bool,
+    /// Trim adapter fragments of at least this many bases that run off
+    /// either read end.
+    min_end_overlap: Option<usize>,
+    end_error_rate: f64,
+    /// Extract a UMI next to detected adapters.
+    umi_pattern: Option<umi::UmiPattern>,
+    /// Reverse complement reads whose adapter hit is reverse complemented.
+    orient_reads: bool,
+    /// Trim end runs of these bases at least `polyx_min_length` long.
+    polyx_bases: Vec<u8>,
+    polyx_min_length: usize,
+    polyx_error_rate: f64,
+}
+
+impl AdapterConfig {
+    fn accepts(&self, hit: &Alignment) -> bool {
+        let within_error_rate = match self.max_error_rate {
+            Some(rate) => {
+                let budget = (rate * hit.aligned_length() as f64).floor() as usize;
+                hit.mismatches + hit.indels <= budget
+            }
+            None => true,
+        };
+        hit.aligned_length() >= self.min_match
+            && within_error_rate
+            && self.max_mismatches.is_none_or(|max| hit.mismatches <= max)
+            && self.max_indels.is_none_or(|max| hit.indels <= max)
+            && hit.score >= self.min_score
+            && hit.identity() >= self.min_identity
+    }
+
+    /// Like [`accepts`](Self::accepts), but the hit must also cover all of a
+    /// `length`-base query except for its error budget. Used for barcodes
+    /// and primers, whose partial hits are too easily confused.
+    fn accepts_full_length(&self, hit: &Alignment, length: usize) -> bool {
+        let budget = (self.max_error_rate.unwrap_or(0.0) * length as f64).floor() as usize;
+        hit.aligned_length() + budget >= length && self.accepts(hit)
+    }
+}
+
+/// GC-content and ambiguous-base limits; unset limits are not checked.
+struct CompositionFilter {
+    min_gc: Option<f64>,
+    max_gc: Option<f64>,
+    max_n_fraction: Option<f64>,
+    max_n_run: Option<usize>,
+}
+
+impl CompositionFilter {
+    fn is_active(&self) -> bool {
+        self.min_gc.is_some() || self.max_gc.is_some() || self.max_n_fraction.is_some() || self.max_n_run.is_some()
+    }
+}
+
+/// Base counts of a read for the GC and N filters.
+struct BaseComposition {
+    length: usize,
+    gc: usize,
+    /// Bases that are A, C, G or T (or U).
+    called: usize,
+    n: usize,
+    longest_n_run: usize,
+}
+
+impl BaseComposition {
+    fn of(sequence: &str) -> Self {
+        let mut composition = BaseComposition { length: sequence.len(), gc: 0, called: 0, n: 0, longest_n_run: 0 };
+        let mut n_run = 0;
+        for base in sequence.bytes() {
+            match base.to_ascii_uppercase() {
+                b'G' | b'C' => {
+                    composition.gc += 1;
+                    composition.called += 1;
+                }
+                b'A' | b'T' | b'U' => composition.called += 1,
+                b'N' => {
+                    composition.n += 1;
+                    n_run += 1;
+                    composition.longest_n_run = composition.longest_n_run.max(n_run);
+                    continue;
+                }
+                _ => {}
+            }
+            n_run = 0;
+        }
+        composition
+    }
+
+    /// GC fraction of the called bases, ignoring Ns and other ambiguity codes.
+    fn gc_fraction(&self) -> f64 {
+        if self.called == 0 {
+            0.0
+        } else {
+            self.gc as f64 / self.called as f64
+        }
+    }
+
+    fn n_fraction(&self) -> f64 {
+        if self.length == 0 {
+            0.0
+        } else {
+            self.n as f64 / self.length as f64
+        }
+    }
+}
+
+/// Direct-RNA poly(A) tail handling.
+struct RnaConfig {
+    tail_error_rate: f64,
+    /// Remove the tail and the 3' adapter after it.
+    trim_tail: bool,
+    min_tail_length: usize,
+}
+
+struct FilterOptions<'a> {
+    input_file: &'a str,
+    output_file: &'a str,
     batch_size: usize,
     min_quality: f64,
+    quality_metric: QualityMetric,
+    /// Threshold for `QualityMetric::ExpectedErrors`, which replaces `min_quality`.
+    max_expected_errors: Option<f64>,
+    /// Filter reads whose header score and computed quality differ by more.
+    max_quality_discrepancy: Option<f64>,
+    /// Replaces `min_quality` with a threshold that depends on read length.
+    quality_by_length: Option<LengthThresholds>,
     min_length: usize,
-    adapter_sequence: Option<&str>,
-    min_adapter_match: usize,
-    max_mismatches: usize,
-    max_indels: usize,
+    adapters: Vec<Adapter>,
+    adapter_config: AdapterConfig,
+    demux: Option<demux::DemuxConfig>,
+    amplicon: Option<amplicon::AmpliconConfig>,
+    cdna: Option<cdna::CdnaConfig>,
+    rna: Option<RnaConfig>,
+    complexity: Option<complexity::ComplexityFilter>,
+    composition: CompositionFilter,
+    /// Duplicates are only looked for among reads that pass every other step.
+    dedup: Option<dedup::DedupConfig>,
+    /// Reads selected by ID; the others are dropped before any other step.
+    ids: Option<ids::IdFilter>,
+    metadata: Option<metadata::MetadataConfig>,
+    /// Output records must satisfy this `--filter-expr`.
+    filter_expr: Option<FilterExpr>,
     debug_mode: bool,
-) -> Result<(), IoError> {
-    let input_path = std::path::Path::new(input_file);
-    let output_path = std::path::Path::new(output_file);
+}
 
-    let mut buf = [0; 2];
-    let mut input_file_for_check = File::open(input_path).expect("Failed to open input file");
-    input_file_for_check
-        .read_exact(&mut buf)
-        .expect("Failed to read first two bytes");
-    let reader: Box<dyn BufRead> = if &buf == b"\x1f\x8b" {
-        Box::new(BufReader::new(flate2::read::GzDecoder::new(File::open(input_path)?)))
+/// Read counts for one output file.
+#[derive(Clone, Default)]
+struct OutputStats {
+    reads: usize,
+    written: usize,
+    filtered: usize,
+    /// Base composition of the reads, with the GC and N limits set.
+    bases: usize,
+    called_bases: usize,
+    gc_bases: usize,
+    n_bases: usize,
+    gc_filtered: usize,
+    n_filtered: usize,
+}
+
+impl OutputStats {
+    fn gc_percent(&self) -> f64 {
+        percent(self.gc_bases, self.called_bases)
+    }
+
+    fn n_percent(&self) -> f64 {
+        percent(self.n_bases, self.bases)
+    }
+}
+
+fn percent(part: usize, whole: usize) -> f64 {
+    if whole == 0 { 0.0 } else { 100.0 * part as f64 / whole as f64 }
+}
+
+/// Counts and output records of a batch, merged into the run totals.
+struct BatchResult {
+    total_reads: usize,
+    filtered_reads: usize,
+    /// Dual-barcoded reads whose ends are not the barcode pair of any
+    /// sample; sent to unclassified.
+    chimeric_reads: usize,
+    /// Reads kept per primer pair in amplicon mode.
+    amplicon_reads: Vec<usize>,
+    /// Amplicon-mode reads without both primers of a pair.
+    missing_primers: usize,
+    /// Amplicon-mode reads with an insert outside the length window.
+    outside_amplicon_length: usize,
+    /// Homopolymer bases trimmed, indexed like `polyx::POLYX_BASES`.
+    polyx_trimmed: [usize; 4],
+    /// Reads judged against, and filtered by, each `--quality-by-length` step.
+    threshold_reads: Vec<usize>,
+    threshold_filtered: Vec<usize>,
+    /// Reads whose header score disagrees with the computed quality.
+    quality_mismatch_reads: usize,
+    /// Reads failing the GC or N limits.
+    gc_filtered_reads: usize,
+    n_filtered_reads: usize,
+    /// Reads rejected as low complexity.
+    low_complexity_reads: usize,
+    duplicate_reads: usize,
+    unselected_reads: usize,
+    missing_metadata_reads: usize,
+    failed_requirement_reads: usize,
+    expr_filtered_reads: usize,
+    /// Bases masked in low-complexity windows.
+    masked_bases: usize,
+    /// RNA-mode reads with a poly(A) tail shorter than the minimum.
+    short_tail_reads: usize,
+    /// cDNA-mode read classes.
+    full_length_reads: usize,
+    rescued_reads: usize,
+    unusable_reads: usize,
+    /// Per output file: one entry per sample, then unclassified, when demultiplexing.
+    outputs: Vec<OutputStats>,
+    lines: Vec<Vec<String>>,
+}
+
+impl BatchResult {
+    fn new(options: &FilterOptions) -> Self {
+        let num_outputs = options.demux.as_ref().map_or(1, |demux| demux.samples.len() + 1);
+        let num_pairs = options.amplicon.as_ref().map_or(0, |amplicon| amplicon.pairs.len());
+        let num_steps = options.quality_by_length.as_ref().map_or(0, |thresholds| thresholds.steps.len());
+        BatchResult {
+            total_reads: 0,
+            filtered_reads: 0,
+            chimeric_reads: 0,
+            amplicon_reads: vec![0; num_pairs],
+            missing_primers: 0,
+            outside_amplicon_length: 0,
+            polyx_trimmed: [0; 4],
+            threshold_reads: vec![0; num_steps],
+            threshold_filtered: vec![0; num_steps],
+            quality_mismatch_reads: 0,
+            gc_filtered_reads: 0,
+            n_filtered_reads: 0,
+            low_complexity_reads: 0,
+            duplicate_reads: 0,
+            unselected_reads: 0,
+            missing_metadata_reads: 0,
+            failed_requirement_reads: 0,
+            expr_filtered_reads: 0,
+            masked_bases: 0,
+            short_tail_reads: 0,
+            full_length_reads: 0,
+            rescued_reads: 0,
+            unusable_reads: 0,
+            outputs: vec![OutputStats::default(); num_outputs],
+            lines: vec![Vec::new(); num_outputs],
+        }
+    }
+
+    fn merge(mut self, other: BatchResult) -> Self {
+        self.total_reads += other.total_reads;
+        self.filtered_reads += other.filtered_reads;
+        self.chimeric_reads += other.chimeric_reads;
+        for (reads, other) in self.amplicon_reads.iter_mut().zip(other.amplicon_reads) {
+            *reads += other;
+        }
+        self.missing_primers += other.missing_primers;
+        self.outside_amplicon_length += other.outside_amplicon_length;
+        for (trimmed, other) in self.polyx_trimmed.iter_mut().zip(other.polyx_trimmed) {
+            *trimmed += other;
+        }
+        for (reads, other) in self.threshold_reads.iter_mut().zip(other.threshold_reads) {
+            *reads += other;
+        }
+        for (filtered, other) in self.threshold_filtered.iter_mut().zip(other.threshold_filtered) {
+            *filtered += other;
+        }
+        self.quality_mismatch_reads += other.quality_mismatch_reads;
+        self.gc_filtered_reads += other.gc_filtered_reads;
+        self.n_filtered_reads += other.n_filtered_reads;
+        self.low_complexity_reads += other.low_complexity_reads;
+        self.duplicate_reads += other.duplicate_reads;
+        self.unselected_reads += other.unselected_reads;
+        self.missing_metadata_reads += other.missing_metadata_reads;
+        self.failed_requirement_reads += other.failed_requirement_reads;
+        self.expr_filtered_reads += other.expr_filtered_reads;
+        self.masked_bases += other.masked_bases;
+        self.short_tail_reads += other.short_tail_reads;
+        self.full_length_reads += other.full_length_reads;
+        self.rescued_reads += other.rescued_reads;
+        self.unusable_reads += other.unusable_reads;
+        for (stats, other) in self.outputs.iter_mut().zip(other.outputs) {
+            stats.reads += other.reads;
+            stats.written += other.written;
+            stats.filtered += other.filtered;
+            stats.bases += other.bases;
+            stats.called_bases += other.called_bases;
+            stats.gc_bases += other.gc_bases;
+            stats.n_bases += other.n_bases;
+            stats.gc_filtered += other.gc_filtered;
+            stats.n_filtered += other.n_filtered;
+        }
+        for (lines, other) in self.lines.iter_mut().zip(other.lines) {
+            lines.extend(other);
+        }
+        self
+    }
+
+    fn write_record(&mut self, output: usize, header: String, sequence: String, quality: String) {
+        self.outputs[output].written += 1;
+        self.lines[output].extend([header, sequence, "+".to_string(), quality]);
+    }
+
+    fn filter_record(&mut self, output: usize) {
+        self.outputs[output].filtered += 1;
+        self.filtered_reads += 1;
+    }
+
+    /// Whether a single record's result writes anything.
+    fn writes_output(&self) -> bool {
+        self.lines.iter().any(|lines| !lines.is_empty())
+    }
+
+    /// A single record's result with its output records dropped as a
+    /// duplicate. Its counts are kept, except that every record it would
+    /// have written, including each `_partN`, is filtered instead.
+    fn into_duplicate(mut self) -> BatchResult {
+        for stats in &mut self.outputs {
+            stats.filtered += stats.written;
+            self.filtered_reads += stats.written;
+            stats.written = 0;
+        }
+        for lines in &mut self.lines {
+            lines.clear();
+        }
+        self.duplicate_reads += 1;
+        self
+    }
+}
+
+/// Names of the output files: the sample files followed by the unclassified
+/// file when demultiplexing, otherwise just `output_file`.
+fn output_names(options: &FilterOptions) -> Vec<String> {
+    match &options.demux {
+        Some(demux) => demux
+            .samples
+            .iter()
+            .map(|sample| sample.name.as_str())
+            .chain(["unclassified"])
+            .map(|sample| demux::sample_output_path(options.output_file, sample))
+            .collect(),
+        None => vec![options.output_file.to_string()],
+    }
+}
+
+/// Appends SAM-style `TAG:TYPE:VALUE` tags to a FASTQ header.
+fn tag_header(header: &str, tags: &[String]) -> String {
+    if tags.is_empty() {
+        header.to_string()
     } else {
-        Box::new(BufReader::new(File::open(input_path)?))
+        format!("{} {}", header, tags.join(" "))
+    }
+}
+
+/// Narrows a record line to `range`, still borrowing the input line when
+/// nothing has rewritten it yet.
+fn narrow(text: Cow<'_, str>, range: std::ops::Range<usize>) -> Cow<'_, str> {
+    match text {
+        Cow::Borrowed(text) => Cow::Borrowed(&text[range]),
+        Cow::Owned(mut text) => {
+            text.truncate(range.end);
+            text.drain(..range.start);
+            Cow::Owned(text)
+        }
+    }
+}
+
+fn process_record(chunk: &[String], options: &FilterOptions, batch: &mut BatchResult) {
+    let header = &chunk[0];
+    let mut sequence = Cow::Borrowed(chunk[1].as_str());
+    let mut quality_line = Cow::Borrowed(chunk[3].as_str());
+    let debug_mode = options.debug_mode;
+    let mut tags = Vec::new();
+    batch.total_reads += 1;
+
+    // Checked before demultiplexing, so these reads aren't in any output's counts.
+    if options.ids.as_ref().is_some_and(|ids| !ids.selects(header)) {
+        if debug_mode {
+            eprintln!("DEBUG: Filtered {} - read ID not selected", header);
+        }
+        batch.unselected_reads += 1;
+        batch.filtered_reads += 1;
+        return;
+    }
+
+    let mut metadata_row = None;
+    if let Some(metadata) = &options.metadata {
+        metadata_row = metadata.table.row(header);
+        match metadata_row {
+            None => {
+                batch.missing_metadata_reads += 1;
+                if !metadata.requirements.is_empty() {
+                    if debug_mode {
+                        eprintln!("DEBUG: Filtered {} - no metadata row", header);
+                    }
+                    batch.filtered_reads += 1;
+                    return;
+                }
+            }
+            Some(row) => {
+                if let Some(requirement) = metadata.requirements.iter().find(|requirement| !requirement.holds(row)) {
+                    if debug_mode {
+                        eprintln!("DEBUG: Filtered {} - metadata {}={} fails requirement",
+                            header, metadata.table.columns[requirement.column], row[requirement.column]);
+                    }
+                    batch.failed_requirement_reads += 1;
+                    batch.filtered_reads += 1;
+                    return;
+                }
+                tags.extend(metadata.tags(row));
+            }
+        }
+    }
+
+    let output = match &options.demux {
+        Some(demux) => match demux.assign(&sequence, &quality_line) {
+            BarcodeCall::Assigned { sample, start, end } => {
+                if debug_mode {
+                    for hit in start.iter().chain(end.iter()) {
+                        let orientation = if hit.reverse_complement { "reverse complement" } else { "forward" };
+                        let end = if hit.at_start { "5'" } else { "3'" };
+                        eprintln!("DEBUG: Barcode {} found in {} at position {} ({}, {} end)",
+                            demux.barcodes[hit.barcode].name, header, hit.alignment.read_start, orientation, end);
+                    }
+                }
+                // Dual-barcoded reads lose their barcodes and carry the sample instead.
+                if demux.require_both_ends {
+                    let (trim_start, trim_end) = demux.trim_range(sequence.len(), start, end);
+                    sequence = narrow(sequence, trim_start..trim_end);
+                    quality_line = narrow(quality_line, trim_start..trim_end);
+                    tags.push(format!("BC:Z:{}", demux.samples[sample].name));
+                }
+                sample
+            }
+            BarcodeCall::Chimeric { start, end } => {
+                if debug_mode {
+                    eprintln!("DEBUG: Chimeric read {} - barcode {} at 5' end, {} at 3' end",
+                        header, demux.barcodes[start].name, demux.barcodes[end].name);
+                }
+                batch.chimeric_reads += 1;
+                demux.samples.len()
+            }
+            BarcodeCall::Unclassified => {
+                if debug_mode {
+                    eprintln!("DEBUG: No unambiguous barcode in {}", header);
+                }
+                demux.samples.len()
+            }
+        },
+        None => 0,
     };
+    batch.outputs[output].reads += 1;
 
-    let output_file = File::create(output_path)?;
-    let writer = Arc::new(Mutex::new(GzEncoder::new(BufWriter::new(output_file), Compression::default())));
+    if let Some(amplicon) = &options.amplicon {
+        let Some(hit) = amplicon.find(&sequence, &quality_line) else {
+            if debug_mode {
+                eprintln!("DEBUG: Filtered {} - no primer pair found", header);
+            }
+            batch.missing_primers += 1;
+            batch.filter_record(output);
+            return;
+        };
+        let pair = &amplicon.pairs[hit.pair].name;
+        let insert_length = hit.insert_end.saturating_sub(hit.insert_start);
+        if !amplicon.in_length_window(insert_length) {
+            if debug_mode {
+                eprintln!("DEBUG: Filtered {} - primer pair {} insert length {} outside amplicon length window", header, pair, insert_length);
+            }
+            batch.outside_amplicon_length += 1;
+            batch.filter_record(output);
+            return;
+        }
+        if debug_mode {
+            let strand = if hit.minus_strand { "-" } else { "+" };
+            eprintln!("DEBUG: Primer pair {} found in {} ({} strand, insert {}..{})", pair, header, strand, hit.insert_start, hit.insert_end);
+        }
+        sequence = narrow(sequence, hit.insert_start..hit.insert_end);
+        quality_line = narrow(quality_line, hit.insert_start..hit.insert_end);
+        if hit.minus_strand {
+            let (rc_sequence, rc_quality) = reverse_complement_record(&sequence, &quality_line);
+            (sequence, quality_line) = (Cow::Owned(rc_sequence), Cow::Owned(rc_quality));
+        }
+        batch.amplicon_reads[hit.pair] += 1;
+    }
 
-    let total_reads = Arc::new(Mutex::new(0));
-    let filtered_reads = Arc::new(Mutex::new(0));
+    // The header score is only needed when it is the metric or is checked
+    // against the computed one.
+    let metric = options.quality_metric;
+    let computed_quality = metric.compute(&quality_line);
+    let header_quality = if computed_quality.is_none() || options.max_quality_discrepancy.is_some() {
+        match get_quality_value(header) {
+            Ok(val) => Some(val),
+            Err(e) => {
+                if debug_mode {
+                    eprintln!("DEBUG: Failed to parse quality value from {}: {}", header, e);
+                }
+                batch.filter_record(output);
+                return;
+            }
+        }
+    } else {
+        None
+    };
 
-    let mut batch_start = 0;
+    if let (Some(max_discrepancy), Some(header_quality)) = (options.max_quality_discrepancy, header_quality) {
+        // The header score describes the whole read, so it is compared
+        // before any barcode or primer trimming.
+        let computed = metric.phred_quality(&chunk[3]);
+        if (header_quality - computed).abs() > max_discrepancy {
+            if debug_mode {
+                eprintln!("DEBUG: Filtered {} - header quality {} disagrees with computed quality {:.2}", header, header_quality, computed);
+            }
+            batch.quality_mismatch_reads += 1;
+            batch.filter_record(output);
+            return;
+        }
+    }
 
-    let mut lines_iter = reader.lines();
-    loop {
-        let lines: Vec<_> = lines_iter.by_ref().take(batch_size * 4).collect::<Result<Vec<_>, _>>()?;
-        if lines.is_empty() {
-            break;
+    if metric == QualityMetric::ExpectedErrors {
+        let expected_errors = computed_quality.unwrap();
+        let max_expected_errors = options.max_expected_errors.unwrap_or(f64::INFINITY);
+        if expected_errors > max_expected_errors {
+            if debug_mode {
+                eprintln!("DEBUG: Filtered {} - expected errors {:.2} > {}", header, expected_errors, max_expected_errors);
+            }
+            batch.filter_record(output);
+            return;
+        }
+    } else {
+        let quality_value = computed_quality.or(header_quality).unwrap();
+        let min_quality = match &options.quality_by_length {
+            Some(thresholds) => {
+                let step = thresholds.step_for(sequence.len());
+                let min_quality = thresholds.steps[step].1;
+                if debug_mode {
+                    eprintln!("DEBUG: {} judged against quality threshold {} (length {})", header, min_quality, sequence.len());
+                }
+                batch.threshold_reads[step] += 1;
+                if quality_value < min_quality {
+                    batch.threshold_filtered[step] += 1;
+                }
+                min_quality
+            }
+            None => options.min_quality,
+        };
+        if quality_value < min_quality {
+            if debug_mode {
+                let shown = match computed_quality {
+                    Some(value) => format!("{:.2}", value),
+                    None => quality_value.to_string(),
+                };
+                eprintln!("DEBUG: Filtered {} - {} {} < {}", header, metric.label(), shown, min_quality);
+            }
+            batch.filter_record(output);
+            return;
         }
+    }
 
-        let batch_end = batch_start + lines.len() / 4;
+    let filter = &options.composition;
+    if filter.is_active() {
+        let composition = BaseComposition::of(&sequence);
+        let stats = &mut batch.outputs[output];
+        stats.bases += composition.length;
+        stats.called_bases += composition.called;
+        stats.gc_bases += composition.gc;
+        stats.n_bases += composition.n;
 
-        let writer_clone = Arc::clone(&writer);
-        let (local_total, local_filtered, output_lines) = lines.par_chunks(4)
-            .fold(
-                || (0, 0, Vec::new()),
-                |(total, mut filtered, mut output_lines), chunk| {
-                    let header = &chunk[0];
-                    let sequence = &chunk[1];
-                    let quality_line = &chunk[3];
-                    let quality_value = match get_quality_value(header) {   
-                        Ok(val) => val,
-                        Err(e) => {
-                            if debug_mode {
-                                eprintln!("DEBUG: Failed to parse quality value from {}: {}", header, e);
-                            }
-                            return (total + chunk.len() / 4, filtered + 1, output_lines);
-                        }
-                    };
+        // Ns first: a read of mostly Ns has no meaningful GC content.
+        let n_fraction = composition.n_fraction();
+        if filter.max_n_fraction.is_some_and(|max| n_fraction > max) || filter.max_n_run.is_some_and(|max| composition.longest_n_run > max) {
+            if debug_mode {
+                eprintln!("DEBUG: Filtered {} - N fraction {:.3}, longest N run {}", header, n_fraction, composition.longest_n_run);
+            }
+            stats.n_filtered += 1;
+            batch.n_filtered_reads += 1;
+            batch.filter_record(output);
+            return;
+        }
+        let gc = composition.gc_fraction();
+        if filter.min_gc.is_some_and(|min| gc < min) || filter.max_gc.is_some_and(|max| gc > max) {
+            if debug_mode {
+                eprintln!("DEBUG: Filtered {} - GC content {:.3} outside range", header, gc);
+            }
+            stats.gc_filtered += 1;
+            batch.gc_filtered_reads += 1;
+            batch.filter_record(output);
+            return;
+        }
+    }
+
+    if let Some(complexity) = &options.complexity {
+        if complexity.mask {
+            let masked = complexity.mask(sequence.to_mut());
+            if debug_mode && masked > 0 {
+                eprintln!("DEBUG: Masked {} low-complexity bases in {}", masked, header);
+            }
+            batch.masked_bases += masked;
+        } else if complexity.is_low_complexity(&sequence) {
+            if debug_mode {
+                eprintln!("DEBUG: Filtered {} - complexity score {:.1} fails threshold {}", header, complexity.score(&sequence), complexity.threshold);
+            }
+            batch.low_complexity_reads += 1;
+            batch.filter_record(output);
+            return;
+        }
+    }
 
-                    if quality_value >= min_quality && sequence.len() >= min_length {
-                        if let Some(adapter) = adapter_sequence {
-                            let processed_seqs = process_adapter_sequence(
-                                header, sequence, quality_line, adapter, 
-                                min_adapter_match, max_mismatches, max_indels, debug_mode
-                            );
-                            
-                            for (processed_header, processed_seq, processed_qual) in processed_seqs {
-                                if processed_seq.len() >= min_length {
-                                    output_lines.push(processed_header);
-                                    output_lines.push(processed_seq);
-                                    output_lines.push("+".to_string());
-                                    output_lines.push(processed_qual);
-                                } else {
-                                    if debug_mode {
-                                        eprintln!("DEBUG: Filtered {} - trimmed length {} < {}", processed_header, processed_seq.len(), min_length);
-                                    }
-                                    filtered += 1;
-                                }
-                            }
-                        } else {
-                            output_lines.extend(chunk.iter().map(|s| s.clone()));
-                        }
+    // Adapters are searched for once per read; a search done for the RNA
+    // step is kept for the adapter step unless cDNA mode splits the read.
+    let searches_adapters = !options.adapters.is_empty() || !options.adapter_config.polyx_bases.is_empty();
+    let mut scan = None;
+    if let Some(rna) = &options.rna {
+        let read_scan = searches_adapters.then(|| scan_adapters(header, &sequence, &quality_line, options));
+        let anchor = read_scan.as_ref().map_or(sequence.len(), |scan| polya_anchor(scan, sequence.len()));
+        let tail = polyx::end_run_length(&sequence.as_bytes()[..anchor], b'A', rna.tail_error_rate, true);
+        if debug_mode {
+            eprintln!("DEBUG: Poly(A) tail of {} bases in {} ending at position {}", tail, header, anchor);
+        }
+        if tail < rna.min_tail_length {
+            if debug_mode {
+                eprintln!("DEBUG: Filtered {} - poly(A) tail {} < {}", header, tail, rna.min_tail_length);
+            }
+            batch.short_tail_reads += 1;
+            batch.filter_record(output);
+            return;
+        }
+        tags.push(format!("pt:i:{}", tail));
+        let mut read_scan = read_scan;
+        if rna.trim_tail {
+            sequence = narrow(sequence, 0..anchor - tail);
+            quality_line = narrow(quality_line, 0..anchor - tail);
+            read_scan = read_scan.map(|scan| scan.truncate(anchor - tail));
+        }
+        if options.cdna.is_none() {
+            scan = read_scan;
+        }
+    }
+
+    let records = match &options.cdna {
+        Some(cdna) => {
+            let (class, segments) = cdna.classify(&sequence, &quality_line);
+            if debug_mode {
+                eprintln!("DEBUG: cDNA read {} is {} with {} segment(s)", header, class.label(), segments.len());
+            }
+            match class {
+                CdnaClass::FullLength => batch.full_length_reads += 1,
+                CdnaClass::Rescued => batch.rescued_reads += 1,
+                CdnaClass::Unusable => {
+                    batch.unusable_reads += 1;
+                    batch.filter_record(output);
+                    return;
+                }
+            }
+            // Reads with several transcripts are split like adapter hits.
+            let split = segments.len() > 1;
+            segments
+                .iter()
+                .enumerate()
+                .map(|(index, segment)| {
+                    let header = if split { format!("{}_part{}", header, index + 1) } else { header.clone() };
+                    let sequence = &sequence[segment.start..segment.end];
+                    let quality = &quality_line[segment.start..segment.end];
+                    let (sequence, quality) = if segment.minus_strand {
+                        reverse_complement_record(sequence, quality)
                     } else {
-                        if debug_mode {
-                            if quality_value < min_quality {
-                                eprintln!("DEBUG: Filtered {} - quality {} < {}", header, quality_value, min_quality);
-                            } else {
-                                eprintln!("DEBUG: Filtered {} - length {} < {}", header, sequence.len(), min_length);
-                            }
-                        }
-                        filtered += 1;
-                    }
-                    
-                    (total + chunk.len() / 4, filtered, output_lines)
+                        (sequence.to_string(), quality.to_string())
+                    };
+                    (header, Cow::Owned(sequence), Cow::Owned(quality))
+                })
+                .collect()
+        }
+        None => vec![(header.clone(), sequence, quality_line)],
+    };
+
+    for (header, sequence, quality_line) in records {
+        if sequence.len() < options.min_length {
+            if debug_mode {
+                eprintln!("DEBUG: Filtered {} - length {} < {}", header, sequence.len(), options.min_length);
+            }
+            batch.filter_record(output);
+            continue;
+        }
+
+        let mut tags = tags.clone();
+        let mut adapter_hits = 0;
+        let processed_seqs = if searches_adapters {
+            let scan = scan.take().unwrap_or_else(|| scan_adapters(&header, &sequence, &quality_line, options));
+            adapter_hits = scan.hits;
+            for (total, trimmed) in batch.polyx_trimmed.iter_mut().zip(scan.polyx_trimmed) {
+                *total += trimmed;
+            }
+            process_adapter_sequence(&header, &sequence, &quality_line, &scan, options, &mut tags)
+            .into_iter()
+            .map(|(header, sequence, quality)| (header, Cow::Owned(sequence), Cow::Owned(quality)))
+            .collect()
+        } else {
+            vec![(header, sequence, quality_line)]
+        };
+
+        for (processed_header, processed_seq, processed_qual) in processed_seqs {
+            if processed_seq.len() < options.min_length {
+                if debug_mode {
+                    eprintln!("DEBUG: Filtered {} - trimmed length {} < {}", processed_header, processed_seq.len(), options.min_length);
                 }
-            )
-            .reduce(
-                || (0, 0, Vec::new()),
-                |(total1, filtered1, mut lines1), (total2, filtered2, lines2)| {
-                    lines1.extend(lines2);
-                    (total1 + total2, filtered1 + filtered2, lines1)
+                batch.filter_record(output);
+                continue;
+            }
+            if let Some(filter_expr) = &options.filter_expr {
+                let lookup = |variable: &Variable| match variable {
+                    Variable::Column(name) => {
+                        let column = options.metadata.as_ref()?.table.column(name)?;
+                        metadata_row.map(|row| Value::Text(row[column].to_string()))
+                    }
+                    _ => expr_variable(variable, &chunk[0], &processed_seq, &processed_qual, adapter_hits, options.quality_metric),
+                };
+                if !filter_expr.matches(&lookup) {
+                    if debug_mode {
+                        eprintln!("DEBUG: Filtered {} - filter expression is false", processed_header);
+                    }
+                    batch.expr_filtered_reads += 1;
+                    batch.filter_record(output);
+                    continue;
                 }
-            );
-
-        if !output_lines.is_empty() {
-            let mut writer_guard = writer_clone.lock().unwrap();
-            for line in &output_lines {
-                writeln!(&mut *writer_guard, "{}", line)?;
             }
+            batch.write_record(output, tag_header(&processed_header, &tags), processed_seq.into_owned(), processed_qual.into_owned());
         }
+    }
+}
+
+/// Value of a `--filter-expr` variable for an output record; `header` is the
+/// read's original header. Metadata columns are looked up by the caller.
+fn expr_variable(
+    variable: &Variable,
+    header: &str,
+    sequence: &str,
+    quality: &str,
+    adapter_hits: usize,
+    metric: QualityMetric,
+) -> Option<Value> {
+    let number = match variable {
+        Variable::Length => sequence.len() as f64,
+        Variable::HeaderQuality => get_quality_value(header).ok()?,
+        Variable::ComputedQuality => metric.phred_quality(quality),
+        Variable::Gc => BaseComposition::of(sequence).gc_fraction(),
+        Variable::NFraction => BaseComposition::of(sequence).n_fraction(),
+        Variable::AdapterHits => adapter_hits as f64,
+        Variable::Field(key) => return expr::header_field(header, key).map(|value| Value::Text(value.to_string())),
+        Variable::Column(_) => return None,
+    };
+    Some(Value::Number(number))
+}
 
-        {
-            let mut total_reads_guard = total_reads.lock().unwrap();
-            *total_reads_guard += local_total;
+/// Processes the records of one batch of FASTQ lines.
+fn process_batch(lines: &[String], options: &FilterOptions, deduplicator: Option<&mut dedup::Deduplicator>) -> BatchResult {
+    match deduplicator {
+        None => lines.par_chunks(4)
+            .fold(
+                || BatchResult::new(options),
+                |mut batch, chunk| {
+                    process_record(chunk, options, &mut batch);
+                    batch
+                }
+            )
+            .reduce(|| BatchResult::new(options), BatchResult::merge),
+        // Duplicates depend on all earlier reads that were kept, so each
+        // record is processed on its own and then checked in input order.
+        Some(deduplicator) => {
+            let records: Vec<BatchResult> = lines.par_chunks(4)
+                .map(|chunk| {
+                    let mut record = BatchResult::new(options);
+                    process_record(chunk, options, &mut record);
+                    record
+                })
+                .collect();
+            let kept: Vec<bool> = records.iter().map(BatchResult::writes_output).collect();
+            let duplicates = deduplicator.find_duplicates(lines, &kept);
+            let mut batch = BatchResult::new(options);
+            for ((record, duplicate), chunk) in records.into_iter().zip(duplicates).zip(lines.chunks(4)) {
+                batch = batch.merge(if duplicate {
+                    if options.debug_mode {
+                        eprintln!("DEBUG: Filtered {} - duplicate read", chunk[0]);
+                    }
+                    record.into_duplicate()
+                } else {
+                    record
+                });
+            }
+            batch
         }
-        {
-            let mut filtered_reads_guard = filtered_reads.lock().unwrap();
-            *filtered_reads_guard += local_filtered;
+    }
+}
+
+fn filter_fastq_by_quality_and_length(options: &FilterOptions) -> Result<(), IoError> {
+    let input_path = std::path::Path::new(options.input_file);
+    let reader = open_input(input_path)?;
+
+    let output_names = output_names(options);
+    let mut writers = Vec::with_capacity(output_names.len());
+    for name in &output_names {
+        let output_file = File::create(name)?;
+        writers.push(GzEncoder::new(BufWriter::new(output_file), Compression::default()));
+    }
+
+    let mut totals = BatchResult::new(options);
+    let mut deduplicator = options.dedup.as_ref().map(dedup::Deduplicator::new);
+
+    let mut lines_iter = reader.lines();
+    loop {
+        let lines: Vec<_> = lines_iter.by_ref().take(options.batch_size * 4).collect::<Result<Vec<_>, _>>()?;
+        if lines.is_empty() {
+            break;
         }
 
-        batch_start = batch_end;
+        let mut batch = process_batch(&lines, options, deduplicator.as_mut());
+
+        for (writer, output_lines) in writers.iter_mut().zip(std::mem::take(&mut batch.lines)) {
+            for line in output_lines {
+                writeln!(writer, "{}", line)?;
+            }
+        }
+        totals = totals.merge(batch);
     }
 
-    let mut writer_guard = writer.lock().unwrap();
-    writer_guard.flush()?;
+    for writer in &mut writers {
+        writer.flush()?;
+    }
 
-    let total_reads = *total_reads.lock().unwrap();
-    let filtered_reads = *filtered_reads.lock().unwrap();
-    println!("Total reads: {}", total_reads);
-    println!("Filtered reads: {}", filtered_reads);
+    println!("Total reads: {}", totals.total_reads);
+    println!("Filtered reads: {}", totals.filtered_reads);
+    if let Some(amplicon) = &options.amplicon {
+        println!("Reads without primer pair: {}", totals.missing_primers);
+        println!("Reads outside amplicon length: {}", totals.outside_amplicon_length);
+        for (pair, reads) in amplicon.pairs.iter().zip(&totals.amplicon_reads) {
+            println!("Primer pair {}: {} reads", pair.name, reads);
+        }
+    }
+    for &base in &options.adapter_config.polyx_bases {
+        let index = polyx::POLYX_BASES.iter().position(|&b| b == base).unwrap();
+        println!("Poly-{} bases trimmed: {}", base as char, totals.polyx_trimmed[index]);
+    }
+    if let Some(thresholds) = &options.quality_by_length {
+        for (step, &(length, min_quality)) in thresholds.steps.iter().enumerate() {
+            println!("Quality threshold {} for reads >= {} bp: {} reads, {} filtered",
+                min_quality, length, totals.threshold_reads[step], totals.threshold_filtered[step]);
+        }
+    }
+    if options.max_quality_discrepancy.is_some() {
+        println!("Reads with header/computed quality mismatch: {}", totals.quality_mismatch_reads);
+    }
+    if options.composition.is_active() {
+        let sum = |count: fn(&OutputStats) -> usize| totals.outputs.iter().map(count).sum::<usize>();
+        println!("GC content: {:.2}%", percent(sum(|stats| stats.gc_bases), sum(|stats| stats.called_bases)));
+        println!("N bases: {:.4}%", percent(sum(|stats| stats.n_bases), sum(|stats| stats.bases)));
+        println!("Reads outside GC range: {}", totals.gc_filtered_reads);
+        println!("Reads with too many Ns: {}", totals.n_filtered_reads);
+    }
+    if let Some(complexity) = &options.complexity {
+        if complexity.mask {
+            println!("Low-complexity bases masked: {}", totals.masked_bases);
+        } else {
+            println!("Low-complexity reads: {}", totals.low_complexity_reads);
+        }
+    }
+    if let Some(dedup) = &options.dedup {
+        println!("Duplicate reads ({}): {}", dedup.mode.label(), totals.duplicate_reads);
+    }
+    if options.ids.is_some() {
+        println!("Reads not selected by ID: {}", totals.unselected_reads);
+    }
+    if let Some(metadata) = &options.metadata {
+        println!("Reads without metadata: {}", totals.missing_metadata_reads);
+        if !metadata.requirements.is_empty() {
+            println!("Reads failing metadata requirements: {}", totals.failed_requirement_reads);
+        }
+    }
+    if options.filter_expr.is_some() {
+        println!("Records failing filter expression: {}", totals.expr_filtered_reads);
+    }
+    if options.rna.is_some() {
+        println!("Reads with short poly(A) tail: {}", totals.short_tail_reads);
+    }
+    if options.cdna.is_some() {
+        println!("Full-length reads: {}", totals.full_length_reads);
+        println!("Rescued reads: {}", totals.rescued_reads);
+        println!("Unusable reads: {}", totals.unusable_reads);
+    }
+    if let Some(demux) = &options.demux {
+        if demux.require_both_ends {
+            println!("Chimeric reads: {}", totals.chimeric_reads);
+        }
+        // Unselected IDs and reads failing --require are dropped before the
+        // read is assigned, so they are in none of the per-output counts below.
+        let requires_metadata = options.metadata.as_ref().is_some_and(|metadata| !metadata.requirements.is_empty());
+        if options.ids.is_some() || requires_metadata {
+            let assigned: usize = totals.outputs.iter().map(|stats| stats.reads).sum();
+            println!("Reads dropped before demultiplexing: {}", totals.total_reads - assigned);
+        }
+        for (name, stats) in output_names.iter().zip(&totals.outputs) {
+            print!("{}: {} reads, {} written, {} filtered", name, stats.reads, stats.written, stats.filtered);
+            if options.composition.is_active() {
+                print!(", GC {:.2}%, N {:.4}%, {} outside GC range, {} with too many Ns",
+                    stats.gc_percent(), stats.n_percent(), stats.gc_filtered, stats.n_filtered);
+            }
+            println!();
+        }
+    }
 
     Ok(())
 }
 
 
-fn get_quality_value(header: &String) -> Result<f64, String> {
+/// Opens a plain or gzip-compressed input file.
+fn open_input(input_path: &std::path::Path) -> Result<Box<dyn BufRead>, IoError> {
+    let mut buf = [0; 2];
+    let mut input_file_for_check = File::open(input_path)?;
+    input_file_for_check.read_exact(&mut buf)?;
+    let reader: Box<dyn BufRead> = if &buf == b"\x1f\x8b" {
+        Box::new(BufReader::new(flate2::read::GzDecoder::new(File::open(input_path)?)))
+    } else {
+        Box::new(BufReader::new(File::open(input_path)?))
+    };
+    Ok(reader)
+}
+
+/// Reads all records of a (possibly gzipped) FASTA file as `(header, sequence)`,
+/// with the leading `>` stripped from the header.
+fn read_fasta(path: &std::path::Path) -> Result<Vec<(String, String)>, IoError> {
+    let mut records: Vec<(String, String)> = Vec::new();
+    for line in open_input(path)?.lines() {
+        let line = line?;
+        let line = line.trim();
+        if let Some(header) = line.strip_prefix('>') {
+            records.push((header.to_string(), String::new()));
+        } else if !line.is_empty() {
+            match records.last_mut() {
+                Some((_, sequence)) => sequence.push_str(line),
+                None => {
+                    return Err(IoError::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("{}: sequence before first FASTA header", path.display()),
+                    ))
+                }
+            }
+        }
+    }
+    Ok(records)
+}
+
+fn get_quality_value(header: &str) -> Result<f64, String> {
     let parts: Vec<&str> = header.split('_').collect();
     if let Some(last_part) = parts.last() {
         // Handle cases where there might be additional text after the quality value
@@ -158,155 +1013,283 @@ fn get_quality_value(header: &String) -> Result<f64, String> {
     }
 }
 
+/// Complement of a nucleotide or IUPAC ambiguity code, preserving case.
+fn complement(c: char) -> char {
+    let upper = match c.to_ascii_uppercase() {
+        'A' => 'T',
+        'T' | 'U' => 'A',
+        'G' => 'C',
+        'C' => 'G',
+        'R' => 'Y', // A/G <-> C/T
+        'Y' => 'R',
+        'K' => 'M', // G/T <-> A/C
+        'M' => 'K',
+        'B' => 'V', // not A <-> not T
+        'V' => 'B',
+        'D' => 'H', // not C <-> not G
+        'H' => 'D',
+        'S' => 'S',
+        'W' => 'W',
+        'N' => 'N',
+        _ => return c, // Keep unknown characters as-is
+    };
+    if c.is_ascii_lowercase() {
+        upper.to_ascii_lowercase()
+    } else {
+        upper
+    }
+}
+
 fn reverse_complement(sequence: &str) -> String {
-    sequence.chars()
-        .rev()
-        .map(|c| match c {
-            'A' => 'T',
-            'T' => 'A',
-            'G' => 'C',
-            'C' => 'G',
-            'a' => 't',
-            't' => 'a',
-            'g' => 'c',
-            'c' => 'g',
-            'N' => 'N',
-            'n' => 'n',
-            _ => c, // Keep unknown characters as-is
-        })
-        .collect()
+    sequence.chars().rev().map(complement).collect()
+}
+
+/// Reverse complement of a FASTQ record: the sequence is reverse
+/// complemented and the quality string reversed to stay aligned with it.
+fn reverse_complement_record(sequence: &str, quality: &str) -> (String, String) {
+    (reverse_complement(sequence), quality.chars().rev().collect())
 }
 
-fn smith_waterman_align(
+/// Aligns `adapter` against `sequence[start..end]` and returns the hit in
+/// read coordinates if it passes the acceptance thresholds.
+fn align_adapter_in_region(
     sequence: &str,
+    quality: &str,
     adapter: &str,
-    match_score: i32,
-    mismatch_penalty: i32,
-    gap_penalty: i32,
-    max_mismatches: usize,
-    max_indels: usize,
-) -> Option<(usize, usize, usize, usize)> {
-    let seq_chars: Vec<char> = sequence.chars().collect();
-    let adapter_chars: Vec<char> = adapter.chars().collect();
-    let m = seq_chars.len();
-    let n = adapter_chars.len();
-    
-    if m == 0 || n == 0 {
+    start: usize,
+    end: usize,
+    config: &AdapterConfig,
+) -> Option<Alignment> {
+    let region_quality = quality.get(start..end).unwrap_or("");
+    let mut hit = smith_waterman_align(&sequence[start..end], region_quality, adapter, &config.scoring)?;
+    if !config.accepts(&hit) {
         return None;
     }
-    
-    let mut matrix = vec![vec![0; n + 1]; m + 1];
-    let mut max_score = 0;
-    let mut max_i = 0;
-    let mut max_j = 0;
-    
-    for i in 1..=m {
-        for j in 1..=n {
-            let match_val = if seq_chars[i-1] == adapter_chars[j-1] {
-                matrix[i-1][j-1] + match_score
-            } else {
-                matrix[i-1][j-1] + mismatch_penalty
-            };
-            
-            let delete = matrix[i-1][j] + gap_penalty;
-            let insert = matrix[i][j-1] + gap_penalty;
-            
-            matrix[i][j] = 0.max(match_val).max(delete).max(insert);
-            
-            if matrix[i][j] > max_score {
-                max_score = matrix[i][j];
-                max_i = i;
-                max_j = j;
+    hit.read_start += start;
+    hit.read_end += start;
+    Some(hit)
+}
+
+fn detect_adapter_position(
+    sequence: &str,
+    quality: &str,
+    adapter: &str,
+    config: &AdapterConfig,
+) -> Option<(Alignment, bool)> {
+    if adapter.len() < config.min_match {
+        return None;
+    }
+
+    // false indicates forward orientation, true reverse complement
+    let rev_comp_adapter = reverse_complement(adapter);
+    let orientations = [(adapter, false), (rev_comp_adapter.as_str(), true)];
+    let len = sequence.len();
+
+    if let Some(window) = config.search_window {
+        if window * 2 < len {
+            for (query, is_reverse_complement) in orientations {
+                let head = align_adapter_in_region(sequence, quality, query, 0, window, config);
+                let tail = align_adapter_in_region(sequence, quality, query, len - window, len, config);
+                let best = match (head, tail) {
+                    (Some(h), Some(t)) => Some(if t.score > h.score { t } else { h }),
+                    (h, t) => h.or(t),
+                };
+                if let Some(hit) = best {
+                    return Some((hit, is_reverse_complement));
+                }
+            }
+            if !config.internal_scan {
+                return None;
             }
         }
     }
-    
-    if max_score <= 0 {
-        return None;
+
+    for (query, is_reverse_complement) in orientations {
+        if let Some(hit) = align_adapter_in_region(sequence, quality, query, 0, len, config) {
+            return Some((hit, is_reverse_complement));
+        }
     }
-    
-    let mut i = max_i;
-    let mut j = max_j;
-    let mut mismatches = 0;
-    let mut indels = 0;
-    
-    while i > 0 && j > 0 && matrix[i][j] > 0 {
-        if matrix[i][j] == matrix[i-1][j-1] + 
-            if seq_chars[i-1] == adapter_chars[j-1] { match_score } else { mismatch_penalty } {
-            if seq_chars[i-1] != adapter_chars[j-1] {
-                mismatches += 1;
-            }
-            i -= 1;
-            j -= 1;
-        } else if matrix[i][j] == matrix[i-1][j] + gap_penalty {
-            indels += 1;
-            i -= 1;
-        } else if matrix[i][j] == matrix[i][j-1] + gap_penalty {
-            indels += 1;
-            j -= 1;
-        } else {
-            break;
+
+    None
+}
+
+/// Best end-anchored adapter fragment in either orientation. With `from_end`
+/// the fragment is searched at the 3' end of the read instead of the 5' end.
+fn best_end_overlap(
+    read: &[u8],
+    adapter: &str,
+    min_overlap: usize,
+    config: &AdapterConfig,
+    from_end: bool,
+) -> Option<Overlap> {
+    let max_error_rate = config.end_error_rate;
+    let read_wildcards = config.scoring.match_read_wildcards;
+    let rev_comp_adapter = reverse_complement(adapter);
+    [adapter, rev_comp_adapter.as_str()]
+        .iter()
+        .filter_map(|query| {
+            if from_end {
+                // Only the read bases the overlap can reach need reversing.
+                let span = read.len().min(overlap_span(query.len(), max_error_rate));
+                let read: Vec<u8> = read[read.len() - span..].iter().rev().copied().collect();
+                let query: Vec<u8> = query.bytes().rev().collect();
+                anchored_overlap(&read, &query, min_overlap, max_error_rate, read_wildcards)
+            } else {
+                anchored_overlap(read, query.as_bytes(), min_overlap, max_error_rate, read_wildcards)
+            }
+        })
+        .max_by_key(|hit| (hit.matches, std::cmp::Reverse(hit.errors)))
+}
+
+/// Range of the read left after trimming adapter fragments that run off
+/// either end.
+fn trim_end_overlaps(sequence: &str, adapters: &[Adapter], config: &AdapterConfig) -> (usize, usize) {
+    let len = sequence.len();
+    let Some(min_overlap) = config.min_end_overlap else {
+        return (0, len);
+    };
+    let read = sequence.as_bytes();
+    let mut start = 0;
+    let mut end = len;
+    for adapter in adapters {
+        if let Some(hit) = best_end_overlap(read, &adapter.sequence, min_overlap, config, false) {
+            start = start.max(hit.read_len);
+        }
+        if let Some(hit) = best_end_overlap(read, &adapter.sequence, min_overlap, config, true) {
+            end = end.min(len - hit.read_len);
         }
     }
-    
-    if mismatches <= max_mismatches && indels <= max_indels {
-        Some((i, max_i, j, max_j))
+    if start >= end {
+        (start, start)
     } else {
-        None
+        (start, end)
     }
 }
 
-fn detect_adapter_position(
-    sequence: &str,
-    adapter: &str,
-    min_match: usize,
-    max_mismatches: usize,
-    max_indels: usize,
-) -> Option<(usize, bool)> {
-    if adapter.len() < min_match {
-        return None;
+/// Where a direct-RNA poly(A) tail ends: at the start of the adapter hit
+/// if it is forward and in the 3' half of the read, or at the read end.
+fn polya_anchor(scan: &AdapterScan, len: usize) -> usize {
+    match scan.detected {
+        Some((_, hit, false)) if hit.read_start >= len / 2 => hit.read_start,
+        _ => len,
     }
-    
-    // Try forward adapter
-    if let Some((start_i, _end_i, start_j, end_j)) = smith_waterman_align(
-        sequence, adapter, 2, -1, -2, max_mismatches, max_indels
-    ) {
-        let aligned_length = end_j - start_j;
-        if aligned_length >= min_match {
-            return Some((start_i, false)); // false indicates forward orientation
+}
+
+/// Adapter search of one record: the range left after trimming partial
+/// adapters and homopolymers at the ends, and the best full adapter hit in
+/// that range, in read coordinates. Counts are only added to the batch
+/// once the record is processed.
+struct AdapterScan<'a> {
+    start: usize,
+    end: usize,
+    detected: Option<(&'a Adapter, Alignment, bool)>,
+    /// Partial adapters trimmed at the ends plus the full hit.
+    hits: usize,
+    polyx_trimmed: [usize; 4],
+}
+
+impl AdapterScan<'_> {
+    /// The scan of the same read cut to its first `len` bases.
+    fn truncate(mut self, len: usize) -> Self {
+        self.end = self.end.min(len);
+        self.start = self.start.min(self.end);
+        if self.detected.is_some_and(|(_, hit, _)| hit.read_end > self.end) {
+            self.detected = None;
         }
+        self
     }
-    
-    // Try reverse complement adapter
-    let rev_comp_adapter = reverse_complement(adapter);
-    if let Some((start_i, _end_i, start_j, end_j)) = smith_waterman_align(
-        sequence, &rev_comp_adapter, 2, -1, -2, max_mismatches, max_indels
-    ) {
-        let aligned_length = end_j - start_j;
-        if aligned_length >= min_match {
-            return Some((start_i, true)); // true indicates reverse complement orientation
+}
+
+fn scan_adapters<'a>(header: &str, sequence: &str, quality: &str, options: &'a FilterOptions) -> AdapterScan<'a> {
+    let config = &options.adapter_config;
+    let debug_mode = options.debug_mode;
+    let mut polyx_trimmed = [0; 4];
+
+    let (start, end) = trim_end_overlaps(sequence, &options.adapters, config);
+    let mut hits = (start > 0) as usize + (end < sequence.len()) as usize;
+    if debug_mode && (start, end) != (0, sequence.len()) {
+        eprintln!("DEBUG: Trimmed partial adapter from {}: {} bases at 5' end, {} bases at 3' end", header, start, sequence.len() - end);
+    }
+
+    let trimmed = &sequence[start..end];
+    let (run_start, run_end) = polyx::trim_runs(
+        trimmed.as_bytes(), &config.polyx_bases, config.polyx_min_length, config.polyx_error_rate, &mut polyx_trimmed
+    );
+    if debug_mode && (run_start, run_end) != (0, trimmed.len()) {
+        eprintln!("DEBUG: Trimmed homopolymer from {}: {} bases at 5' end, {} bases at 3' end", header, run_start, trimmed.len() - run_end);
+    }
+    let (start, end) = (start + run_start, start + run_end);
+    let sequence = &sequence[start..end];
+    let quality = &quality[start..end];
+
+    // With several adapters the highest-scoring hit wins, the first on ties.
+    let mut detected: Option<(&Adapter, Alignment, bool)> = None;
+    for adapter in &options.adapters {
+        if let Some((hit, is_reverse_complement)) = detect_adapter_position(sequence, quality, &adapter.sequence, config) {
+            if detected.is_none_or(|(_, best, _)| hit.score > best.score) {
+                detected = Some((adapter, hit, is_reverse_complement));
+            }
         }
     }
-    
-    None
+    if let Some((_, hit, _)) = &mut detected {
+        hits += 1;
+        hit.read_start += start;
+        hit.read_end += start;
+    }
+    AdapterScan { start, end, detected, hits, polyx_trimmed }
 }
 
+/// Splits a record at its adapter hit from [`scan_adapters`], moving a UMI
+/// next to it into the tags and orienting the read when asked.
 fn process_adapter_sequence(
     header: &str,
     sequence: &str,
     quality: &str,
-    adapter: &str,
-    min_match: usize,
-    max_mismatches: usize,
-    max_indels: usize,
-    debug_mode: bool,
+    scan: &AdapterScan,
+    options: &FilterOptions,
+    tags: &mut Vec<String>,
 ) -> Vec<(String, String, String)> {
+    let config = &options.adapter_config;
+    let debug_mode = options.debug_mode;
     let mut results = Vec::new();
-    
-    if let Some((pos, is_reverse_complement)) = detect_adapter_position(sequence, adapter, min_match, max_mismatches, max_indels) {
+
+    let sequence = &sequence[scan.start..scan.end];
+    let quality = &quality[scan.start..scan.end];
+
+    if let Some((adapter, hit, is_reverse_complement)) = scan.detected {
+        let mut pos = hit.read_start - scan.start;
+        let mut adapter_end = hit.read_end - scan.start;
         if debug_mode {
             let orientation = if is_reverse_complement { "reverse complement" } else { "forward" };
-            eprintln!("DEBUG: Adapter found in {} at position {} ({})", header, pos, orientation);
+            eprintln!("DEBUG: Adapter {} found in {} at position {} ({})", adapter.name, header, pos, orientation);
+        }
+
+        let mut sequence = sequence.to_string();
+        let mut quality = quality.to_string();
+        if let Some(pattern) = &config.umi_pattern {
+            // The UMI sits on the insert side of the adapter.
+            let anchor = if is_reverse_complement { pos } else { adapter_end };
+            if let Some((umi_start, umi_end, umi)) = pattern.extract(&sequence, anchor, is_reverse_complement, &config.scoring) {
+                if debug_mode {
+                    eprintln!("DEBUG: UMI {} found in {} at position {}", umi, header, umi_start);
+                }
+                sequence.replace_range(umi_start..umi_end, "");
+                quality.replace_range(umi_start..umi_end, "");
+                if umi_end <= pos {
+                    pos -= umi_end - umi_start;
+                    adapter_end -= umi_end - umi_start;
+                }
+                tags.push(format!("UMI:Z:{}", umi));
+            }
+        }
+
+        if config.orient_reads {
+            if is_reverse_complement {
+                // The adapter now reads forward; split at its new start.
+                (sequence, quality) = reverse_complement_record(&sequence, &quality);
+                pos = sequence.len() - adapter_end;
+            }
+            tags.push(format!("strand={}", if is_reverse_complement { '-' } else { '+' }));
         }
         
         // Always split at adapter position
@@ -342,6 +1325,61 @@ fn process_adapter_sequence(
 }
 
 
+fn parse_arg<T: std::str::FromStr>(matches: &clap::ArgMatches, name: &str, expected: &str) -> T {
+    let value_str = matches.get_one::<String>(name).unwrap();
+    match value_str.parse() {
+        Ok(value) => value,
+        Err(_) => {
+            eprintln!("Error: invalid value for '{}'. Expected {}.", name, expected);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn parse_optional_arg<T: std::str::FromStr>(matches: &clap::ArgMatches, name: &str, expected: &str) -> Option<T> {
+    matches.get_one::<String>(name).map(|_| parse_arg(matches, name, expected))
+}
+
+/// Like [`parse_arg`], but an option left at its default takes the preset
+/// value when there is one.
+fn parse_arg_or_preset<T: std::str::FromStr>(matches: &clap::ArgMatches, name: &str, expected: &str, preset: Option<T>) -> T {
+    if matches.value_source(name) != Some(clap::parser::ValueSource::CommandLine) {
+        if let Some(value) = preset {
+            return value;
+        }
+    }
+    parse_arg(matches, name, expected)
+}
+
+/// Kits from `--kit-file` (if given) and the built-in presets.
+fn load_kits(matches: &clap::ArgMatches) -> (Vec<kits::Kit>, Vec<kits::Kit>) {
+    let custom_kits = match matches.get_one::<String>("kit_file") {
+        Some(kit_file) => match kits::load_kit_file(std::path::Path::new(kit_file)) {
+            Ok(kits) => kits,
+            Err(e) => {
+                eprintln!("Error: failed to read kit file '{}': {}", kit_file, e);
+                std::process::exit(1);
+            }
+        },
+        None => Vec::new(),
+    };
+    (custom_kits, kits::builtin_kits())
+}
+
+/// Exits with an error unless `input_file` is an existing regular file.
+fn check_input_file(input_file: &str) {
+    let input_file_path = std::path::Path::new(input_file);
+    if !input_file_path.exists() {
+        eprintln!("Error: input file '{}' does not exist.", input_file);
+        std::process::exit(1);
+    }
+
+    if !input_file_path.is_file() {
+        eprintln!("Error: '{}' is not a file.", input_file);
+        std::process::exit(1);
+    }
+}
+
 fn main() {
     // let default_batch_size: usize = 10000;
     let matches = clap::Command::new("fastq-filter")
@@ -359,160 +1397,1313 @@ fn main() {
              .short('q')
              .long("min-quality")
              .required(false)
-             .default_value("7.0")
-             .help("Minimum quality score"))
-        .arg(clap::Arg::new("min_length")
-             .short('l')
-             .long("min-length")
+             .default_value("7.0")
+             .help("Minimum quality score"))
+        .arg(clap::Arg::new("quality_metric")
+             .long("quality-metric")
+             .required(false)
+             .default_value("header")
+             .help("Read quality compared with --min-quality: header, mean, median, min-window:W, or expected-errors (with --max-expected-errors)"))
+        .arg(clap::Arg::new("max_expected_errors")
+             .long("max-expected-errors")
+             .required(false)
+             .help("Maximum expected errors per read for --quality-metric expected-errors"))
+        .arg(clap::Arg::new("quality_by_length")
+             .long("quality-by-length")
+             .required(false)
+             .help("Length-dependent minimum quality as length:quality steps, e.g. \"0:10,5000:8,50000:6\"; replaces --min-quality"))
+        .arg(clap::Arg::new("max_quality_discrepancy")
+             .long("max-quality-discrepancy")
+             .required(false)
+             .help("Filter reads whose header score and computed quality differ by more than this, compared on the untrimmed read; expected errors are compared as mean quality"))
+        .arg(clap::Arg::new("min_length")
+             .short('l')
+             .long("min-length")
+             .required(false)
+             .default_value("1000")
+             .help("Minimum sequence length"))
+        .arg(clap::Arg::new("num_cpus")
+             .short('c')
+             .long("cpus")
+             .required(false)
+             .default_value("2")
+             .help("Number of cpus to use"))
+        .arg(clap::Arg::new("batch_size")
+             .short('b')
+             .long("batch-size")
+             .required(false)
+             .default_value("10000")
+             .help("Batch size for processing"))
+        .arg(clap::Arg::new("adapter")
+             .short('a')
+             .long("adapter")
+             .required(false)
+             .help("Adapter sequence to detect and remove"))
+        .arg(clap::Arg::new("adapter_file")
+             .long("adapter-file")
+             .required(false)
+             .help("FASTA file of adapter sequences to detect and remove"))
+        .arg(clap::Arg::new("kit")
+             .short('k')
+             .long("kit")
+             .required(false)
+             .help("Use the adapters, barcodes, primers and recommended thresholds of a kit preset (see list-kits)"))
+        .arg(clap::Arg::new("kit_file")
+             .long("kit-file")
+             .required(false)
+             .help("Load extra kit presets from a TOML or FASTA file, selected with --kit"))
+        .arg(clap::Arg::new("min_adapter_match")
+             .short('m')
+             .long("min-adapter-match")
+             .required(false)
+             .default_value("10")
+             .help("Minimum adapter match length"))
+        .arg(clap::Arg::new("max_mismatches")
+             .short('x')
+             .long("max-mismatches")
+             .required(false)
+             .help("Maximum allowed mismatches in adapter alignment [default: 2, no cap with --adapter-error-rate]"))
+        .arg(clap::Arg::new("max_indels")
+             .short('d')
+             .long("max-indels")
+             .required(false)
+             .help("Maximum allowed indels in adapter alignment [default: 1, no cap with --adapter-error-rate]"))
+        .arg(clap::Arg::new("adapter_error_rate")
+             .short('e')
+             .long("adapter-error-rate")
+             .required(false)
+             .help("Maximum mismatches + indels per aligned adapter base, e.g. 0.15"))
+        .arg(clap::Arg::new("match_score")
+             .long("match-score")
+             .required(false)
+             .default_value("2")
+             .help("Score for a matching base in adapter alignment"))
+        .arg(clap::Arg::new("mismatch_penalty")
+             .long("mismatch-penalty")
+             .required(false)
+             .default_value("1")
+             .help("Penalty for a mismatching base in adapter alignment"))
+        .arg(clap::Arg::new("mismatch_quality_cap")
+             .long("mismatch-quality-cap")
+             .required(false)
+             .help("Scale mismatch penalties by read base quality, reaching the full penalty at this Phred score"))
+        .arg(clap::Arg::new("match_read_wildcards")
+             .long("match-read-wildcards")
+             .required(false)
+             .action(clap::ArgAction::SetTrue)
+             .help("Let IUPAC codes such as N in reads match adapter bases; by default only adapter codes are wildcards"))
+        .arg(clap::Arg::new("gap_open")
+             .long("gap-open")
+             .required(false)
+             .default_value("2")
+             .help("Penalty for opening a gap in adapter alignment"))
+        .arg(clap::Arg::new("gap_extend")
+             .long("gap-extend")
+             .required(false)
+             .default_value("2")
+             .help("Penalty for extending a gap in adapter alignment"))
+        .arg(clap::Arg::new("min_adapter_score")
+             .long("min-adapter-score")
+             .required(false)
+             .default_value("0")
+             .help("Minimum alignment score for an adapter hit"))
+        .arg(clap::Arg::new("min_adapter_identity")
+             .long("min-adapter-identity")
+             .required(false)
+             .default_value("0.0")
+             .help("Minimum identity (0-1) for an adapter hit"))
+        .arg(clap::Arg::new("adapter_search_window")
+             .short('w')
+             .long("adapter-search-window")
+             .required(false)
+             .help("Only search the first and last N bases of each read for adapters"))
+        .arg(clap::Arg::new("adapter_internal_scan")
+             .long("adapter-internal-scan")
+             .required(false)
+             .action(clap::ArgAction::SetTrue)
+             .help("With --adapter-search-window, scan the whole read when no adapter is found at the ends"))
+        .arg(clap::Arg::new("min_end_overlap")
+             .long("min-end-overlap")
+             .required(false)
+             .help("Trim partial adapters of at least N bases that run off either read end"))
+        .arg(clap::Arg::new("end_error_rate")
+             .long("end-error-rate")
+             .required(false)
+             .default_value("0.1")
+             .help("Maximum errors per adapter base for end-anchored partial adapters"))
+        .arg(clap::Arg::new("trim_polyx")
+             .long("trim-polyx")
+             .required(false)
+             .help("Trim homopolymer runs of these bases from read ends, e.g. A,T"))
+        .arg(clap::Arg::new("polyx_min_length")
+             .long("polyx-min-length")
+             .required(false)
+             .default_value("10")
+             .help("Minimum length of a homopolymer run to trim"))
+        .arg(clap::Arg::new("polyx_error_rate")
+             .long("polyx-error-rate")
+             .required(false)
+             .default_value("0.1")
+             .help("Fraction of other bases tolerated in a trimmed homopolymer run"))
+        .arg(clap::Arg::new("umi_pattern")
+             .long("umi-pattern")
+             .required(false)
+             .help("Move the UMI found next to an adapter into the header, e.g. FLANK + NNNNNNNNNNNN + PRIMER"))
+        .arg(clap::Arg::new("umi_error_rate")
+             .long("umi-error-rate")
+             .required(false)
+             .default_value("0.15")
+             .help("Maximum errors per base in the UMI flank and primer"))
+        .arg(clap::Arg::new("orient_reads")
+             .long("orient-reads")
+             .required(false)
+             .action(clap::ArgAction::SetTrue)
+             .help("Reverse complement reads with a reverse-complement adapter hit and tag headers with strand=+/-"))
+        .arg(clap::Arg::new("barcodes")
+             .long("barcodes")
+             .required(false)
+             .help("FASTA file of sample barcodes; writes one output per barcode plus unclassified reads"))
+        .arg(clap::Arg::new("barcode_search_window")
+             .long("barcode-search-window")
+             .required(false)
+             .default_value("150")
+             .help("Search the first and last N bases of each read for barcodes"))
+        .arg(clap::Arg::new("barcode_error_rate")
+             .long("barcode-error-rate")
+             .required(false)
+             .default_value("0.15")
+             .help("Maximum mismatches + indels per barcode base"))
+        .arg(clap::Arg::new("barcode_score_gap")
+             .long("barcode-score-gap")
+             .required(false)
+             .default_value("6")
+             .help("Minimum score lead of the best barcode over the next best; closer calls are unclassified"))
+        .arg(clap::Arg::new("dual_barcodes")
+             .long("dual-barcodes")
+             .required(false)
+             .action(clap::ArgAction::SetTrue)
+             .help("Require a sample's barcodes at both ends, forward at the 5' end and reverse complemented at the 3' end; trims them and tags headers with BC:Z:<sample>"))
+        .arg(clap::Arg::new("barcode_pairs")
+             .long("barcode-pairs")
+             .required(false)
+             .requires("dual_barcodes")
+             .help("With --dual-barcodes, tab-separated samples (name, 5' barcode, 3' barcode) naming barcodes from --barcodes or the kit [default: each barcode paired with itself]"))
+        .arg(clap::Arg::new("allow_single_barcode")
+             .long("allow-single-barcode")
+             .required(false)
+             .action(clap::ArgAction::SetTrue)
+             .requires("dual_barcodes")
+             .help("With --dual-barcodes, also assign reads with a barcode at only one end if it belongs to a single sample"))
+        .arg(clap::Arg::new("barcode_flank")
+             .long("barcode-flank")
+             .required(false)
+             .default_value("0")
+             .help("With --dual-barcodes, number of adapter bases between barcode and insert to trim with the barcode"))
+        .arg(clap::Arg::new("primers")
+             .long("primers")
+             .required(false)
+             .help("Amplicon mode: tab-separated primer pairs (name, forward, reverse), added to those of the kit; keep reads with both primers, trimmed and oriented forward"))
+        .arg(clap::Arg::new("primer_search_window")
+             .long("primer-search-window")
+             .required(false)
+             .default_value("200")
+             .help("Search the first and last N bases of each read for primers"))
+        .arg(clap::Arg::new("primer_error_rate")
+             .long("primer-error-rate")
+             .required(false)
+             .default_value("0.15")
+             .help("Maximum mismatches + indels per primer base"))
+        .arg(clap::Arg::new("min_amplicon_length")
+             .long("min-amplicon-length")
+             .required(false)
+             .default_value("0")
+             .help("Minimum insert length between the primers"))
+        .arg(clap::Arg::new("max_amplicon_length")
+             .long("max-amplicon-length")
+             .required(false)
+             .help("Maximum insert length between the primers"))
+        .arg(clap::Arg::new("cdna")
+             .long("cdna")
              .required(false)
-             .default_value("1000")
-             .help("Minimum sequence length"))
-        .arg(clap::Arg::new("num_cpus")
-             .short('c')
-             .long("cpus")
+             .action(clap::ArgAction::SetTrue)
+             .help("cDNA mode: keep full-length and rescued reads between SSP and VNP primers, trimmed and oriented to the transcript strand"))
+        .arg(clap::Arg::new("ssp")
+             .long("ssp")
              .required(false)
-             .default_value("2")
-             .help("Number of cpus to use"))
-        .arg(clap::Arg::new("batch_size")
-             .short('b')
-             .long("batch-size")
+             .help("Strand-switching primer for --cdna [default: the kit's SSP primer]"))
+        .arg(clap::Arg::new("vnp")
+             .long("vnp")
              .required(false)
-             .default_value("10000")
-             .help("Batch size for processing"))
-        .arg(clap::Arg::new("adapter")
-             .short('a')
-             .long("adapter")
+             .help("Oligo-dT VNP primer for --cdna [default: the kit's VNP primer]"))
+        .arg(clap::Arg::new("rna")
+             .long("rna")
              .required(false)
-             .help("Adapter sequence to detect and remove"))
-        .arg(clap::Arg::new("min_adapter_match")
-             .short('m')
-             .long("min-adapter-match")
+             .action(clap::ArgAction::SetTrue)
+             .help("Direct-RNA mode: estimate the poly(A) tail before the 3' adapter and tag headers with pt:i:<length>"))
+        .arg(clap::Arg::new("polya_error_rate")
+             .long("polya-error-rate")
              .required(false)
-             .default_value("10")
-             .help("Minimum adapter match length"))
-        .arg(clap::Arg::new("max_mismatches")
-             .short('x')
-             .long("max-mismatches")
+             .default_value("0.1")
+             .help("Fraction of non-A bases tolerated in the poly(A) tail"))
+        .arg(clap::Arg::new("trim_polya")
+             .long("trim-polya")
              .required(false)
-             .default_value("2")
-             .help("Maximum allowed mismatches in adapter alignment"))
-        .arg(clap::Arg::new("max_indels")
-             .short('d')
-             .long("max-indels")
+             .action(clap::ArgAction::SetTrue)
+             .requires("rna")
+             .help("With --rna, trim the poly(A) tail and the 3' adapter after it"))
+        .arg(clap::Arg::new("min_polya_length")
+             .long("min-polya-length")
              .required(false)
-             .default_value("1")
-             .help("Maximum allowed indels in adapter alignment"))
+             .default_value("0")
+             .help("With --rna, filter reads with a shorter poly(A) tail"))
+        .arg(clap::Arg::new("min_gc")
+             .long("min-gc")
+             .required(false)
+             .help("Minimum GC fraction (0-1) of the called bases"))
+        .arg(clap::Arg::new("max_gc")
+             .long("max-gc")
+             .required(false)
+             .help("Maximum GC fraction (0-1) of the called bases"))
+        .arg(clap::Arg::new("max_n_fraction")
+             .long("max-n-fraction")
+             .required(false)
+             .help("Maximum fraction (0-1) of N bases"))
+        .arg(clap::Arg::new("max_n_run")
+             .long("max-n-run")
+             .required(false)
+             .help("Maximum length of a run of N bases"))
+        .arg(clap::Arg::new("min_complexity")
+             .long("min-complexity")
+             .required(false)
+             .help("Reject low-complexity reads: minimum entropy, or maximum DUST score with --complexity-method dust (both 0-100)"))
+        .arg(clap::Arg::new("complexity_method")
+             .long("complexity-method")
+             .required(false)
+             .default_value("entropy")
+             .value_parser(["entropy", "dust"])
+             .help("Complexity score for --min-complexity"))
+        .arg(clap::Arg::new("mask_low_complexity")
+             .long("mask-low-complexity")
+             .required(false)
+             .action(clap::ArgAction::SetTrue)
+             .requires("min_complexity")
+             .help("Mask low-complexity 64-base windows with N instead of rejecting reads"))
+        .arg(clap::Arg::new("dedup")
+             .long("dedup")
+             .required(false)
+             .value_parser(["ids", "exact", "minhash"])
+             .help("Drop repeated read IDs, identical sequences, or near-identical sequences (minhash), keeping the first read that passes the other filters; ids and exact use about 32 bytes per unique read, minhash about 2 KB"))
+        .arg(clap::Arg::new("dedup_memory")
+             .long("dedup-memory")
+             .required(false)
+             .requires("dedup")
+             .help("Memory limit in MB, at least 1: for --dedup ids or exact, a Bloom filter of this size instead of an exact hash set; for minhash, the most sketches kept for comparison"))
+        .arg(clap::Arg::new("dedup_similarity")
+             .long("dedup-similarity")
+             .required(false)
+             .requires("dedup")
+             .help("Minimum 12-mer Jaccard similarity of near-identical reads for --dedup minhash [default: 0.5]"))
+        .arg(clap::Arg::new("include_ids")
+             .long("include-ids")
+             .required(false)
+             .help("Keep only reads whose ID is listed in this file, one ID per line"))
+        .arg(clap::Arg::new("exclude_ids")
+             .long("exclude-ids")
+             .required(false)
+             .help("Drop reads whose ID is listed in this file, one ID per line"))
+        .arg(clap::Arg::new("id_regex")
+             .long("id-regex")
+             .required(false)
+             .help("Keep only reads whose ID matches this regular expression"))
+        .arg(clap::Arg::new("metadata")
+             .long("metadata")
+             .required(false)
+             .help("Tab-separated per-read table whose columns can be used with --require, --filter-expr and --metadata-tags"))
+        .arg(clap::Arg::new("id_column")
+             .long("id-column")
+             .required(false)
+             .default_value("read_id")
+             .requires("metadata")
+             .help("Column of the --metadata table holding the read ID"))
+        .arg(clap::Arg::new("require")
+             .long("require")
+             .required(false)
+             .action(clap::ArgAction::Append)
+             .requires("metadata")
+             .help("Keep only reads whose metadata meets this condition, e.g. taxid=9606 or identity>=0.95; repeatable"))
+        .arg(clap::Arg::new("metadata_tags")
+             .long("metadata-tags")
+             .required(false)
+             .requires("metadata")
+             .help("Comma-separated metadata columns to add to output headers as column=value, with whitespace and % percent-encoded"))
+        .arg(clap::Arg::new("filter_expr")
+             .long("filter-expr")
+             .required(false)
+             .help("Keep only records for which this expression holds, e.g. 'len >= 1000 && q >= 7 && (gc < 0.65 || field.ch in [1..200])'; header fields are field.<key>, metadata columns go by name"))
         .arg(clap::Arg::new("debug")
              .short('D')
              .long("debug")
              .required(false)
              .action(clap::ArgAction::SetTrue)
              .help("Enable debug output with detailed filtering information"))
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
+        .subcommand(clap::Command::new("discover-adapters")
+             .about("Find candidate adapters from k-mers over-represented at read ends")
+             .arg(clap::Arg::new("input_file")
+                  .short('i')
+                  .long("input")
+                  .required(true)
+                  .help("Input FASTQ file"))
+             .arg(clap::Arg::new("output_file")
+                  .short('o')
+                  .long("output")
+                  .required(false)
+                  .help("Output FASTA file of candidate adapters [default: stdout]"))
+             .arg(clap::Arg::new("num_reads")
+                  .short('n')
+                  .long("num-reads")
+                  .required(false)
+                  .default_value("10000")
+                  .help("Number of reads to sample"))
+             .arg(clap::Arg::new("end_length")
+                  .short('e')
+                  .long("end-length")
+                  .required(false)
+                  .default_value("100")
+                  .help("Number of bases at each read end to count k-mers in"))
+             .arg(clap::Arg::new("kmer_size")
+                  .short('k')
+                  .long("kmer-size")
+                  .required(false)
+                  .default_value("12")
+                  .help("K-mer size"))
+             .arg(clap::Arg::new("min_frequency")
+                  .short('f')
+                  .long("min-frequency")
+                  .required(false)
+                  .default_value("0.01")
+                  .help("Minimum fraction of sampled reads a k-mer must occur in"))
+             .arg(clap::Arg::new("top")
+                  .short('t')
+                  .long("top")
+                  .required(false)
+                  .default_value("10")
+                  .help("Maximum number of candidate adapters to report")))
+        .subcommand(clap::Command::new("list-kits")
+             .about("Print the built-in kit presets and any loaded with --kit-file")
+             .arg(clap::Arg::new("kit_file")
+                  .long("kit-file")
+                  .required(false)
+                  .help("Load extra kit presets from a TOML or FASTA file")))
         .get_matches();
 
+    if let Some(("list-kits", sub_matches)) = matches.subcommand() {
+        let (custom_kits, builtin) = load_kits(sub_matches);
+        kits::print_kits(&custom_kits);
+        kits::print_kits(&builtin);
+        return;
+    }
+
+    if let Some(("discover-adapters", sub_matches)) = matches.subcommand() {
+        let input_file = sub_matches.get_one::<String>("input_file").unwrap();
+        check_input_file(input_file);
+        let kmer_size: usize = parse_arg(sub_matches, "kmer_size", "a positive integer");
+        if !(4..=32).contains(&kmer_size) {
+            eprintln!("Error: invalid value for 'kmer_size'. Expected an integer between 4 and 32.");
+            std::process::exit(1);
+        }
+        let options = discover::DiscoverOptions {
+            input_file,
+            output_file: sub_matches.get_one::<String>("output_file").map(|s| s.as_str()),
+            num_reads: parse_arg(sub_matches, "num_reads", "a positive integer"),
+            end_length: parse_arg(sub_matches, "end_length", "a positive integer"),
+            kmer_size,
+            min_frequency: parse_arg(sub_matches, "min_frequency", "a number between 0 and 1"),
+            top: parse_arg(sub_matches, "top", "a positive integer"),
+        };
+        if let Err(e) = discover::discover_adapters(&options) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let input_file = matches.get_one::<String>("input_file").unwrap();
     let output_file = matches.get_one::<String>("output_file").unwrap();
+    check_input_file(input_file);
 
-    // Check if the input file can be opened
-    let input_file_path = std::path::Path::new(input_file);
-    if !input_file_path.exists() {
-        eprintln!("Error: input file '{}' does not exist.", input_file);
+    let min_quality: f64 = parse_arg(&matches, "min_quality", "a floating-point number");
+    let min_length: usize = parse_arg(&matches, "min_length", "a positive integer");
+    let quality_metric: QualityMetric = parse_arg(&matches, "quality_metric", "header, mean, median, min-window:W or expected-errors");
+    let max_expected_errors: Option<f64> = parse_optional_arg(&matches, "max_expected_errors", "a non-negative number");
+    if quality_metric == QualityMetric::ExpectedErrors && max_expected_errors.is_none() {
+        eprintln!("Error: --quality-metric expected-errors needs --max-expected-errors.");
         std::process::exit(1);
     }
+    let max_quality_discrepancy: Option<f64> = parse_optional_arg(&matches, "max_quality_discrepancy", "a non-negative number");
+    let quality_by_length = matches.get_one::<String>("quality_by_length").map(|curve| {
+        if quality_metric == QualityMetric::ExpectedErrors {
+            eprintln!("Error: --quality-by-length can't be used with --quality-metric expected-errors.");
+            std::process::exit(1);
+        }
+        match curve.parse::<LengthThresholds>() {
+            Ok(thresholds) => thresholds.with_default(min_quality),
+            Err(e) => {
+                eprintln!("Error: invalid value for 'quality_by_length': {}.", e);
+                std::process::exit(1);
+            }
+        }
+    });
 
-    if !input_file_path.is_file() {
-        eprintln!("Error: '{}' is not a file.", input_file);
+    let all_cpus = num_cpus::get(); // Get the number of available CPUs
+    let num_cpus: usize = parse_arg(&matches, "num_cpus", "a positive integer");
+    if num_cpus > all_cpus {
+        eprintln!("Error: inalid value for 'num_cpus' beacuse it exceed to all avaliable cpus {}", all_cpus);
         std::process::exit(1);
     }
 
-    let min_quality_str = matches.get_one::<String>("min_quality").unwrap();
-    let min_quality: f64 = match min_quality_str.parse() {
-        Ok(value) => value,
-        Err(_) => {
-            eprintln!("Error: invalid value for 'min_quality'. Expected a floating-point number.");
-            std::process::exit(1);
+    let batch_size: usize = parse_arg(&matches, "batch_size", "a positive integer");
+
+    let mut adapters = Vec::new();
+    if let Some(sequence) = matches.get_one::<String>("adapter") {
+        adapters.push(Adapter { name: "adapter".to_string(), sequence: sequence.clone() });
+    }
+    if let Some(adapter_file) = matches.get_one::<String>("adapter_file") {
+        match read_fasta(std::path::Path::new(adapter_file)) {
+            Ok(records) => adapters.extend(records.into_iter().map(|(header, sequence)| Adapter {
+                name: header.split_whitespace().next().unwrap_or("").to_string(),
+                sequence,
+            })),
+            Err(e) => {
+                eprintln!("Error: failed to read adapter file '{}': {}", adapter_file, e);
+                std::process::exit(1);
+            }
         }
+    }
+
+    let (custom_kits, builtin) = load_kits(&matches);
+    let kit = match matches.get_one::<String>("kit") {
+        Some(name) => match custom_kits.iter().chain(builtin.iter()).find(|kit| &kit.name == name) {
+            Some(kit) => Some(kit.clone()),
+            None => {
+                eprintln!("Error: unknown kit '{}'. Run 'cyc_filt list-kits' to see the available kits.", name);
+                std::process::exit(1);
+            }
+        },
+        None => None,
     };
+    let thresholds = kit.as_ref().map(|kit| kit.thresholds.clone()).unwrap_or_default();
+    if let Some(kit) = &kit {
+        adapters.extend(kit.adapters.iter().map(|(name, sequence)| Adapter {
+            name: name.clone(),
+            sequence: sequence.clone(),
+        }));
+    }
 
-    let min_length_str = matches.get_one::<String>("min_length").unwrap();
-    let min_length: usize = match min_length_str.parse() {
-        Ok(value) => value,
-        Err(_) => {
-            eprintln!("Error: invalid value for 'min_length'. Expected a positive integer.");
-            std::process::exit(1);
+    let min_adapter_match: usize = parse_arg_or_preset(&matches, "min_adapter_match", "a positive integer", thresholds.min_adapter_match);
+    let max_error_rate: Option<f64> = parse_optional_arg(&matches, "adapter_error_rate", "a number between 0 and 1")
+        .or(thresholds.adapter_error_rate);
+    if max_error_rate.is_some_and(|rate| !(0.0..1.0).contains(&rate)) {
+        eprintln!("Error: invalid value for 'adapter_error_rate'. Expected a number between 0 and 1.");
+        std::process::exit(1);
+    }
+    // Without an error rate the absolute limits keep their historical defaults;
+    // with one they are only applied when given explicitly.
+    let mut max_mismatches: Option<usize> = parse_optional_arg(&matches, "max_mismatches", "a positive integer");
+    let mut max_indels: Option<usize> = parse_optional_arg(&matches, "max_indels", "a positive integer");
+    if max_error_rate.is_none() {
+        max_mismatches = max_mismatches.or(Some(2));
+        max_indels = max_indels.or(Some(1));
+    }
+
+    let scoring = Scoring {
+        match_score: parse_arg_or_preset(&matches, "match_score", "a positive integer", thresholds.match_score),
+        mismatch_penalty: parse_arg_or_preset(&matches, "mismatch_penalty", "a non-negative integer", thresholds.mismatch_penalty),
+        gap_open: parse_arg_or_preset(&matches, "gap_open", "a non-negative integer", thresholds.gap_open),
+        gap_extend: parse_arg_or_preset(&matches, "gap_extend", "a non-negative integer", thresholds.gap_extend),
+        mismatch_quality_cap: parse_optional_arg(&matches, "mismatch_quality_cap", "a Phred score between 1 and 93"),
+        match_read_wildcards: matches.get_flag("match_read_wildcards"),
+    };
+    if scoring.mismatch_quality_cap.is_some_and(|cap| !(1..=93).contains(&cap)) {
+        eprintln!("Error: invalid value for 'mismatch_quality_cap'. Expected a Phred score between 1 and 93.");
+        std::process::exit(1);
+    }
+    if scoring.match_score <= 0 || scoring.mismatch_penalty < 0 || scoring.gap_open < 0 || scoring.gap_extend < 0 {
+        eprintln!("Error: adapter alignment scores must be non-negative and 'match_score' must be positive.");
+        std::process::exit(1);
+    }
+
+    let min_adapter_identity: f64 = parse_arg_or_preset(&matches, "min_adapter_identity", "a number between 0 and 1", thresholds.min_adapter_identity);
+    if !(0.0..=1.0).contains(&min_adapter_identity) {
+        eprintln!("Error: invalid value for 'min_adapter_identity'. Expected a number between 0 and 1.");
+        std::process::exit(1);
+    }
+
+    let end_error_rate: f64 = parse_arg(&matches, "end_error_rate", "a number between 0 and 1");
+    if !(0.0..1.0).contains(&end_error_rate) {
+        eprintln!("Error: invalid value for 'end_error_rate'. Expected a number between 0 and 1.");
+        std::process::exit(1);
+    }
+
+    let umi_error_rate: f64 = parse_arg(&matches, "umi_error_rate", "a number between 0 and 1");
+    if !(0.0..1.0).contains(&umi_error_rate) {
+        eprintln!("Error: invalid value for 'umi_error_rate'. Expected a number between 0 and 1.");
+        std::process::exit(1);
+    }
+    let umi_pattern = matches.get_one::<String>("umi_pattern").map(|pattern| {
+        match umi::UmiPattern::parse(pattern, umi_error_rate) {
+            Ok(pattern) => pattern,
+            Err(e) => {
+                eprintln!("Error: invalid value for 'umi_pattern': {}.", e);
+                std::process::exit(1);
+            }
         }
+    });
+
+    let polyx_bases: Vec<u8> = match matches.get_one::<String>("trim_polyx") {
+        Some(list) => list
+            .split(',')
+            .map(|base| match base.trim().to_ascii_uppercase().as_bytes() {
+                [base] if polyx::POLYX_BASES.contains(base) => *base,
+                _ => {
+                    eprintln!("Error: invalid value for 'trim_polyx'. Expected a comma-separated list of A, C, G and T.");
+                    std::process::exit(1);
+                }
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+    let polyx_error_rate: f64 = parse_arg(&matches, "polyx_error_rate", "a number between 0 and 1");
+    if !(0.0..1.0).contains(&polyx_error_rate) {
+        eprintln!("Error: invalid value for 'polyx_error_rate'. Expected a number between 0 and 1.");
+        std::process::exit(1);
+    }
+
+    let adapter_search_window: Option<usize> = parse_optional_arg(&matches, "adapter_search_window", "a positive integer")
+        .or(thresholds.adapter_search_window);
+    if adapter_search_window == Some(0) {
+        eprintln!("Error: invalid value for 'adapter_search_window'. Expected a positive integer.");
+        std::process::exit(1);
+    }
+
+    let adapter_config = AdapterConfig {
+        scoring,
+        min_match: min_adapter_match,
+        max_mismatches,
+        max_indels,
+        max_error_rate,
+        min_score: parse_arg_or_preset(&matches, "min_adapter_score", "an integer", thresholds.min_adapter_score),
+        min_identity: min_adapter_identity,
+        search_window: adapter_search_window,
+        internal_scan: matches.get_flag("adapter_internal_scan"),
+        min_end_overlap: parse_optional_arg(&matches, "min_end_overlap", "a positive integer")
+            .or(thresholds.min_end_overlap),
+        end_error_rate,
+        umi_pattern,
+        orient_reads: matches.get_flag("orient_reads"),
+        polyx_bases,
+        polyx_min_length: parse_arg(&matches, "polyx_min_length", "a positive integer"),
+        polyx_error_rate,
     };
 
-    let all_cpus = num_cpus::get(); // Get the number of available CPUs
-    let num_cpus_str = matches.get_one::<String>("num_cpus").unwrap();
-    let num_cpus: usize = match num_cpus_str.parse() {
-        Ok(value) => value,
-        Err(_) => {
-            eprintln!("Error: invalid value for 'num_cpus'. Expected a positive integer.");
+    let mut barcodes = Vec::new();
+    if let Some(barcode_file) = matches.get_one::<String>("barcodes") {
+        match read_fasta(std::path::Path::new(barcode_file)) {
+            Ok(records) => barcodes.extend(records.into_iter().map(|(header, sequence)| demux::Barcode {
+                name: header.split_whitespace().next().unwrap_or("").to_string(),
+                sequence,
+            })),
+            Err(e) => {
+                eprintln!("Error: failed to read barcode file '{}': {}", barcode_file, e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(kit) = &kit {
+        barcodes.extend(kit.barcodes.iter().map(|(name, sequence)| demux::Barcode {
+            name: name.clone(),
+            sequence: sequence.clone(),
+        }));
+    }
+    if let Err(e) = demux::check_names("barcode", barcodes.iter().map(|barcode| barcode.name.as_str())) {
+        eprintln!("Error: invalid barcodes: {}.", e);
+        std::process::exit(1);
+    }
+    let demux = if barcodes.is_empty() {
+        None
+    } else {
+        let barcode_error_rate: f64 = parse_arg(&matches, "barcode_error_rate", "a number between 0 and 1");
+        if !(0.0..1.0).contains(&barcode_error_rate) {
+            eprintln!("Error: invalid value for 'barcode_error_rate'. Expected a number between 0 and 1.");
+            std::process::exit(1);
+        }
+        let samples = match matches.get_one::<String>("barcode_pairs") {
+            Some(pair_file) => match demux::load_barcode_pairs(std::path::Path::new(pair_file), &barcodes) {
+                Ok(samples) if !samples.is_empty() => samples,
+                Ok(_) => {
+                    eprintln!("Error: barcode pair file '{}' has no samples.", pair_file);
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    eprintln!("Error: failed to read barcode pair file '{}': {}", pair_file, e);
+                    std::process::exit(1);
+                }
+            },
+            None => demux::single_samples(&barcodes),
+        };
+        if let Err(e) = demux::check_names("sample", samples.iter().map(|sample| sample.name.as_str())) {
+            eprintln!("Error: invalid barcode pairs: {}.", e);
             std::process::exit(1);
         }
+        Some(demux::DemuxConfig {
+            barcodes,
+            samples,
+            alignment: AdapterConfig {
+                min_match: 0,
+                max_mismatches: None,
+                max_indels: None,
+                max_error_rate: Some(barcode_error_rate),
+                min_score: 0,
+                min_identity: 0.0,
+                search_window: None,
+                internal_scan: false,
+                min_end_overlap: None,
+                umi_pattern: None,
+                ..adapter_config.clone()
+            },
+            search_window: parse_arg(&matches, "barcode_search_window", "a positive integer"),
+            min_score_gap: parse_arg(&matches, "barcode_score_gap", "a non-negative integer"),
+            require_both_ends: matches.get_flag("dual_barcodes"),
+            allow_single_end: matches.get_flag("allow_single_barcode"),
+            flank: parse_arg(&matches, "barcode_flank", "a non-negative integer"),
+        })
     };
-    if num_cpus > all_cpus {
-        eprintln!("Error: inalid value for 'num_cpus' beacuse it exceed to all avaliable cpus {}", all_cpus);
+
+    let primer_error_rate: f64 = parse_arg(&matches, "primer_error_rate", "a number between 0 and 1");
+    if !(0.0..1.0).contains(&primer_error_rate) {
+        eprintln!("Error: invalid value for 'primer_error_rate'. Expected a number between 0 and 1.");
         std::process::exit(1);
     }
+    let primer_alignment = AdapterConfig {
+        min_match: 0,
+        max_mismatches: None,
+        max_indels: None,
+        max_error_rate: Some(primer_error_rate),
+        min_score: 0,
+        min_identity: 0.0,
+        search_window: None,
+        internal_scan: false,
+        min_end_overlap: None,
+        umi_pattern: None,
+        ..adapter_config.clone()
+    };
 
-    let batch_size_str = matches.get_one::<String>("batch_size").unwrap();
-    let batch_size: usize = match batch_size_str.parse() {
-        Ok(value) => value,
-        Err(_) => {
-            eprintln!("Error: invalid value for 'batch_size'. Expected a positive integer.");
-            std::process::exit(1);
+    let mut pairs = Vec::new();
+    if let Some(primer_file) = matches.get_one::<String>("primers") {
+        match amplicon::load_primers(std::path::Path::new(primer_file)) {
+            Ok(records) if !records.is_empty() => pairs.extend(records),
+            Ok(_) => {
+                eprintln!("Error: primer file '{}' has no primer pairs.", primer_file);
+                std::process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("Error: failed to read primer file '{}': {}", primer_file, e);
+                std::process::exit(1);
+            }
         }
+    }
+    if let Some(kit) = &kit {
+        pairs.extend(kit.amplicons.iter().map(|(name, pair)| amplicon::PrimerPair {
+            name: name.clone(),
+            forward: pair.forward.clone(),
+            reverse: pair.reverse.clone(),
+        }));
+    }
+    let amplicon = (!pairs.is_empty()).then(|| amplicon::AmpliconConfig {
+        pairs,
+        alignment: primer_alignment.clone(),
+        search_window: parse_arg(&matches, "primer_search_window", "a positive integer"),
+        min_length: parse_arg(&matches, "min_amplicon_length", "a non-negative integer"),
+        max_length: parse_optional_arg(&matches, "max_amplicon_length", "a positive integer"),
+    });
+
+    let cdna = if matches.get_flag("cdna") {
+        let kit_primer = |name: &str| kit.as_ref().and_then(|kit| kit.primers.get(name).cloned());
+        let ssp = matches.get_one::<String>("ssp").cloned().or_else(|| kit_primer("SSP"));
+        let vnp = matches.get_one::<String>("vnp").cloned().or_else(|| kit_primer("VNP"));
+        let (Some(ssp), Some(vnp)) = (ssp, vnp) else {
+            eprintln!("Error: --cdna needs the SSP and VNP primers, from --ssp/--vnp or a kit with SSP and VNP primers.");
+            std::process::exit(1);
+        };
+        Some(cdna::CdnaConfig { ssp, vnp, alignment: primer_alignment })
+    } else {
+        None
     };
 
-    let adapter_sequence = matches.get_one::<String>("adapter").map(|s| s.as_str());
-    
-    let min_adapter_match_str = matches.get_one::<String>("min_adapter_match").unwrap();
-    let min_adapter_match: usize = match min_adapter_match_str.parse() {
-        Ok(value) => value,
-        Err(_) => {
-            eprintln!("Error: invalid value for 'min_adapter_match'. Expected a positive integer.");
+    let rna = if matches.get_flag("rna") {
+        let tail_error_rate: f64 = parse_arg(&matches, "polya_error_rate", "a number between 0 and 1");
+        if !(0.0..1.0).contains(&tail_error_rate) {
+            eprintln!("Error: invalid value for 'polya_error_rate'. Expected a number between 0 and 1.");
             std::process::exit(1);
         }
+        Some(RnaConfig {
+            tail_error_rate,
+            trim_tail: matches.get_flag("trim_polya"),
+            min_tail_length: parse_arg(&matches, "min_polya_length", "a non-negative integer"),
+        })
+    } else {
+        None
     };
 
+    let composition = CompositionFilter {
+        min_gc: parse_optional_arg(&matches, "min_gc", "a number between 0 and 1"),
+        max_gc: parse_optional_arg(&matches, "max_gc", "a number between 0 and 1"),
+        max_n_fraction: parse_optional_arg(&matches, "max_n_fraction", "a number between 0 and 1"),
+        max_n_run: parse_optional_arg(&matches, "max_n_run", "a non-negative integer"),
+    };
+    for (name, value) in [("min_gc", composition.min_gc), ("max_gc", composition.max_gc), ("max_n_fraction", composition.max_n_fraction)] {
+        if value.is_some_and(|value| !(0.0..=1.0).contains(&value)) {
+            eprintln!("Error: invalid value for '{}'. Expected a number between 0 and 1.", name);
+            std::process::exit(1);
+        }
+    }
+
+    let complexity = parse_optional_arg(&matches, "min_complexity", "a number between 0 and 100").map(|threshold: f64| {
+        if !(0.0..=100.0).contains(&threshold) {
+            eprintln!("Error: invalid value for 'min_complexity'. Expected a number between 0 and 100.");
+            std::process::exit(1);
+        }
+        let method = match matches.get_one::<String>("complexity_method").unwrap().as_str() {
+            "dust" => complexity::ComplexityMethod::Dust,
+            _ => complexity::ComplexityMethod::Entropy,
+        };
+        complexity::ComplexityFilter { method, threshold, mask: matches.get_flag("mask_low_complexity") }
+    });
 
-    let max_mismatches_str = matches.get_one::<String>("max_mismatches").unwrap();
-    let max_mismatches: usize = match max_mismatches_str.parse() {
-        Ok(value) => value,
-        Err(_) => {
-            eprintln!("Error: invalid value for 'max_mismatches'. Expected a positive integer.");
+    let dedup = matches.get_one::<String>("dedup").map(|mode| {
+        let mode = match mode.as_str() {
+            "ids" => dedup::DedupMode::Ids,
+            "exact" => dedup::DedupMode::Exact,
+            _ => dedup::DedupMode::MinHash,
+        };
+        let memory = parse_optional_arg(&matches, "dedup_memory", "a number of MB, at least 1").map(|megabytes: f64| {
+            if megabytes.is_nan() || megabytes < 1.0 {
+                eprintln!("Error: invalid value for 'dedup_memory'. Expected a number of MB, at least 1.");
+                std::process::exit(1);
+            }
+            (megabytes * 1024.0 * 1024.0) as usize
+        });
+        let similarity: Option<f64> = parse_optional_arg(&matches, "dedup_similarity", "a number between 0 and 1");
+        if similarity.is_some() && mode != dedup::DedupMode::MinHash {
+            eprintln!("Error: invalid value for 'dedup_similarity'. Only used with --dedup minhash.");
             std::process::exit(1);
         }
-    };
+        let similarity = similarity.unwrap_or(0.5);
+        if !(0.0..=1.0).contains(&similarity) || similarity == 0.0 {
+            eprintln!("Error: invalid value for 'dedup_similarity'. Expected a number between 0 and 1.");
+            std::process::exit(1);
+        }
+        dedup::DedupConfig { mode, memory, similarity }
+    });
 
-    let max_indels_str = matches.get_one::<String>("max_indels").unwrap();
-    let max_indels: usize = match max_indels_str.parse() {
-        Ok(value) => value,
-        Err(_) => {
-            eprintln!("Error: invalid value for 'max_indels'. Expected a positive integer.");
+    let load_id_list = |name: &str| matches.get_one::<String>(name).map(|id_file| {
+        match ids::load_ids(std::path::Path::new(id_file)) {
+            Ok(ids) => ids,
+            Err(e) => {
+                eprintln!("Error: failed to read ID list '{}': {}", id_file, e);
+                std::process::exit(1);
+            }
+        }
+    });
+    let include = load_id_list("include_ids");
+    let exclude = load_id_list("exclude_ids");
+    let regex = matches.get_one::<String>("id_regex").map(|pattern| match regex::Regex::new(pattern) {
+        Ok(regex) => regex,
+        Err(e) => {
+            eprintln!("Error: invalid value for 'id_regex': {}", e);
             std::process::exit(1);
         }
-    };
+    });
+    let ids = (include.is_some() || exclude.is_some() || regex.is_some()).then_some(ids::IdFilter { include, exclude, regex });
+
+    let metadata = matches.get_one::<String>("metadata").map(|table_file| {
+        let id_column = matches.get_one::<String>("id_column").unwrap();
+        let table = match metadata::MetadataTable::load(std::path::Path::new(table_file), id_column) {
+            Ok(table) => table,
+            Err(e) => {
+                eprintln!("Error: failed to read metadata table '{}': {}", table_file, e);
+                std::process::exit(1);
+            }
+        };
+        let requirements = matches
+            .get_many::<String>("require")
+            .into_iter()
+            .flatten()
+            .map(|spec| match table.requirement(spec) {
+                Ok(requirement) => requirement,
+                Err(e) => {
+                    eprintln!("Error: invalid value for 'require': {}.", e);
+                    std::process::exit(1);
+                }
+            })
+            .collect();
+        let tag_columns = match matches.get_one::<String>("metadata_tags") {
+            Some(names) => names
+                .split(',')
+                .map(|name| match table.column(name.trim()) {
+                    Some(column) => column,
+                    None => {
+                        eprintln!("Error: invalid value for 'metadata_tags': no '{}' column in the metadata table.", name.trim());
+                        std::process::exit(1);
+                    }
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        metadata::MetadataConfig { table, requirements, tag_columns }
+    });
+
+    let columns = metadata.as_ref().map_or(&[][..], |metadata| &metadata.table.columns[..]);
+    let filter_expr = matches.get_one::<String>("filter_expr").map(|source| match FilterExpr::parse(source, columns) {
+        Ok(filter_expr) => filter_expr,
+        Err(e) => {
+            eprintln!("Error: invalid value for 'filter_expr': {}.", e);
+            std::process::exit(1);
+        }
+    });
 
     let debug_mode = matches.get_flag("debug");
 
-    let result = filter_fastq_by_quality_and_length(
-        input_file, 
-        output_file, 
-        num_cpus, 
-        batch_size, 
-        min_quality, 
+    let options = FilterOptions {
+        input_file,
+        output_file,
+        batch_size,
+        min_quality,
+        quality_metric,
+        max_expected_errors,
+        max_quality_discrepancy,
+        quality_by_length,
         min_length,
-        adapter_sequence,
-        min_adapter_match,
-        max_mismatches,
-        max_indels,
-        debug_mode
-    );
+        adapters,
+        adapter_config,
+        demux,
+        amplicon,
+        cdna,
+        rna,
+        complexity,
+        composition,
+        dedup,
+        ids,
+        metadata,
+        filter_expr,
+        debug_mode,
+    };
+
+    let result = filter_fastq_by_quality_and_length(&options);
 
     if let Err(e) = result {
         eprintln!("Error: {}", e);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADAPTER: &str = "ACGTTGCAAGTC";
+
+    /// Adapter settings that accept any hit, for tests to tighten.
+    fn adapter_config() -> AdapterConfig {
+        AdapterConfig {
+            scoring: Scoring::default(),
+            min_match: 0,
+            max_mismatches: None,
+            max_indels: None,
+            max_error_rate: None,
+            min_score: 0,
+            min_identity: 0.0,
+            search_window: None,
+            internal_scan: false,
+            min_end_overlap: None,
+            end_error_rate: 0.1,
+            umi_pattern: None,
+            orient_reads: false,
+            polyx_bases: Vec::new(),
+            polyx_min_length: 10,
+            polyx_error_rate: 0.1,
+        }
+    }
+
+    fn hit(read: &str) -> Alignment {
+        smith_waterman_align(read, "", ADAPTER, &Scoring::default()).unwrap()
+    }
+
+    /// Options that search for `ADAPTER` and apply no other filter.
+    fn options(adapter_config: AdapterConfig) -> FilterOptions<'static> {
+        FilterOptions {
+            input_file: "",
+            output_file: "",
+            batch_size: 1,
+            min_quality: 0.0,
+            quality_metric: QualityMetric::Mean,
+            max_expected_errors: None,
+            max_quality_discrepancy: None,
+            quality_by_length: None,
+            min_length: 0,
+            adapters: vec![Adapter { name: "adapter".to_string(), sequence: ADAPTER.to_string() }],
+            adapter_config,
+            demux: None,
+            amplicon: None,
+            cdna: None,
+            rna: None,
+            complexity: None,
+            composition: CompositionFilter { min_gc: None, max_gc: None, max_n_fraction: None, max_n_run: None },
+            dedup: None,
+            ids: None,
+            metadata: None,
+            filter_expr: None,
+            debug_mode: false,
+        }
+    }
+
+    /// Runs the adapter scan and split over one record.
+    fn split(sequence: &str, quality: &str, options: &FilterOptions) -> (Vec<(String, String, String)>, Vec<String>) {
+        let scan = scan_adapters("@r", sequence, quality, options);
+        let mut tags = Vec::new();
+        let parts = process_adapter_sequence("@r", sequence, quality, &scan, options, &mut tags);
+        (parts, tags)
+    }
+
+    fn sequences(parts: &[(String, String, String)]) -> Vec<&str> {
+        parts.iter().map(|(_, sequence, _)| sequence.as_str()).collect()
+    }
+
+    #[test]
+    fn error_rate_budget_covers_mismatches_and_indels() {
+        // Two mismatches over the 12-base adapter.
+        let two_mismatches = hit("TTTTTACGATGCAAGACGGGGG");
+        assert_eq!((two_mismatches.aligned_length(), two_mismatches.mismatches, two_mismatches.indels), (12, 2, 0));
+        let at = |rate| AdapterConfig { max_error_rate: Some(rate), ..adapter_config() };
+        assert!(!at(0.15).accepts(&two_mismatches));
+        assert!(at(0.2).accepts(&two_mismatches));
+
+        // One mismatch and one deleted base share the same budget.
+        let mixed = hit("TTTTTACGATGAAGTCGGGGG");
+        assert_eq!((mixed.aligned_length(), mixed.mismatches, mixed.indels), (12, 1, 1));
+        assert!(!at(0.15).accepts(&mixed));
+        assert!(at(0.2).accepts(&mixed));
+    }
+
+    #[test]
+    fn per_kind_caps_tighten_the_rate() {
+        let config = AdapterConfig { max_error_rate: Some(0.2), ..adapter_config() };
+        let two_mismatches = hit("TTTTTACGATGCAAGACGGGGG");
+        let deletion = hit("TTTTTACGTTGAAGTCGGGGG");
+        assert_eq!((deletion.mismatches, deletion.indels), (0, 1));
+        assert!(config.accepts(&two_mismatches) && config.accepts(&deletion));
+        assert!(!AdapterConfig { max_mismatches: Some(1), ..config.clone() }.accepts(&two_mismatches));
+        assert!(AdapterConfig { max_mismatches: Some(1), ..config.clone() }.accepts(&deletion));
+        assert!(!AdapterConfig { max_indels: Some(0), ..config.clone() }.accepts(&deletion));
+        assert!(AdapterConfig { max_indels: Some(0), ..config }.accepts(&two_mismatches));
+    }
+
+    #[test]
+    fn internal_adapters_need_internal_scan() {
+        let read = format!("{}{ADAPTER}{}", "A".repeat(40), "A".repeat(40));
+        let quality = "I".repeat(read.len());
+        let windowed = AdapterConfig { min_score: 20, search_window: Some(20), ..adapter_config() };
+        assert!(detect_adapter_position(&read, &quality, ADAPTER, &windowed).is_none());
+
+        let scanning = AdapterConfig { internal_scan: true, ..windowed.clone() };
+        let (found, reverse_complement) = detect_adapter_position(&read, &quality, ADAPTER, &scanning).unwrap();
+        assert_eq!((found.read_start, found.read_end, reverse_complement), (40, 52, false));
+
+        // Within a window the adapter is found either way.
+        let read = format!("{}{ADAPTER}{}", "A".repeat(70), "A".repeat(5));
+        let (found, _) = detect_adapter_position(&read, &quality, ADAPTER, &windowed).unwrap();
+        assert_eq!(found.read_start, 70);
+    }
+
+    #[test]
+    fn complement_covers_every_iupac_code() {
+        let pairs = [
+            ('A', 'T'), ('T', 'A'), ('G', 'C'), ('C', 'G'), ('U', 'A'),
+            ('R', 'Y'), ('Y', 'R'), ('K', 'M'), ('M', 'K'), ('B', 'V'), ('V', 'B'),
+            ('D', 'H'), ('H', 'D'), ('S', 'S'), ('W', 'W'), ('N', 'N'),
+        ];
+        for (base, expected) in pairs {
+            assert_eq!(complement(base), expected, "{}", base);
+            assert_eq!(complement(base.to_ascii_lowercase()), expected.to_ascii_lowercase());
+        }
+        assert_eq!(complement('-'), '-');
+        assert_eq!(reverse_complement("ACGTURYKMBVDHSWNacgtn"), "nacgtNWSDHBVKMRYAACGT");
+        assert_eq!(reverse_complement(&reverse_complement("GATTACArykmbvdhswn")), "GATTACArykmbvdhswn");
+    }
+
+    #[test]
+    fn low_quality_mismatches_can_pass_min_score() {
+        let scoring = Scoring { mismatch_penalty: 4, mismatch_quality_cap: Some(40), ..Scoring::default() };
+        let config = AdapterConfig { scoring, min_score: 16, ..adapter_config() };
+        // Mismatches against adapter bases 4 and 11.
+        let read = "TTTTTACGATGCAAGACGGGGG";
+        let confident = "I".repeat(read.len());
+        let doubtful: String = confident
+            .char_indices()
+            .map(|(i, q)| if i == 8 || i == 15 { '#' } else { q })
+            .collect();
+        assert!(detect_adapter_position(read, &confident, ADAPTER, &config).is_none());
+        let (found, _) = detect_adapter_position(read, &doubtful, ADAPTER, &config).unwrap();
+        assert_eq!((found.score, found.mismatches), (20, 2));
+    }
+
+    #[test]
+    fn umi_removal_keeps_the_split_at_the_adapter() {
+        const FLANK: &str = "CAGCACCT";
+        const PRIMER: &str = "GTTGTACTTCG";
+        const UMI: &str = "GATTACCAGTCC";
+        const OTHER: &str = "TCTCAAGGCTAGCG";
+        let config = AdapterConfig {
+            min_score: 20,
+            umi_pattern: Some(umi::UmiPattern::parse(&format!("{FLANK}{}{PRIMER}", "N".repeat(12)), 0.1).unwrap()),
+            ..adapter_config()
+        };
+
+        // Forward adapter: the UMI is after the split and nothing moves.
+        let read = format!("{OTHER}{ADAPTER}{FLANK}{UMI}{PRIMER}GGATCC");
+        let (parts, tags) = split(&read, &"I".repeat(read.len()), &options(config.clone()));
+        assert_eq!(sequences(&parts), [OTHER.to_string(), format!("{ADAPTER}{FLANK}{PRIMER}GGATCC")]);
+        assert_eq!(tags, [format!("UMI:Z:{UMI}")]);
+
+        // Reverse complement adapter: the UMI comes out before the split.
+        let upstream = reverse_complement(&format!("{FLANK}{UMI}{PRIMER}"));
+        let read = format!("GGATCC{upstream}{}{OTHER}", reverse_complement(ADAPTER));
+        let (parts, tags) = split(&read, &"I".repeat(read.len()), &options(config.clone()));
+        let insert = format!("GGATCC{}", reverse_complement(&format!("{FLANK}{PRIMER}")));
+        assert_eq!(sequences(&parts), [insert.clone(), format!("{}{OTHER}", reverse_complement(ADAPTER))]);
+        assert_eq!(tags, [format!("UMI:Z:{UMI}")]);
+
+        // Oriented, the adapter end moves with the removed UMI too.
+        let oriented = AdapterConfig { orient_reads: true, ..config };
+        let (parts, _) = split(&read, &"I".repeat(read.len()), &options(oriented));
+        assert_eq!(sequences(&parts), [reverse_complement(OTHER), format!("{ADAPTER}{}", reverse_complement(&insert))]);
+    }
+
+    #[test]
+    fn minus_strand_amplicons_are_written_forward() {
+        const FORWARD: &str = "AGAGTTTGATCCTGGCTCAG";
+        const REVERSE: &str = "TACGGTTACCTTGTTACGAC";
+        const INSERT: &str = "GATTACAGATTACACCATGGTTAACCGGTTAACCATGGATCC";
+        let mut options = options(adapter_config());
+        options.adapters.clear();
+        options.amplicon = Some(amplicon::AmpliconConfig {
+            pairs: vec![amplicon::PrimerPair { name: "16S".to_string(), forward: FORWARD.to_string(), reverse: REVERSE.to_string() }],
+            alignment: AdapterConfig { max_error_rate: Some(0.1), ..adapter_config() },
+            search_window: 40,
+            min_length: 0,
+            max_length: None,
+        });
+
+        let read = reverse_complement(&format!("TT{FORWARD}{INSERT}{}CC", reverse_complement(REVERSE)));
+        let quality: String = (0..read.len()).map(|i| (b'!' + 10 + (i % 30) as u8) as char).collect();
+        let chunk = ["@r".to_string(), read, "+".to_string(), quality.clone()];
+        let mut batch = BatchResult::new(&options);
+        process_record(&chunk, &options, &mut batch);
+        assert_eq!(batch.lines[0][1], INSERT);
+        // The insert's quality is reversed along with it.
+        let insert_start = 2 + REVERSE.len();
+        let expected: String = quality[insert_start..insert_start + INSERT.len()].chars().rev().collect();
+        assert_eq!(batch.lines[0][3], expected);
+        assert_eq!(batch.amplicon_reads, [1]);
+    }
+
+    #[test]
+    fn record_reverse_complement_reverses_quality() {
+        assert_eq!(reverse_complement_record("AACGTN", "ABCDEF"), ("NACGTT".to_string(), "FEDCBA".to_string()));
+    }
+
+    #[test]
+    fn orient_flips_reverse_complement_hits() {
+        const INSERT: &str = "GGATCCTTAGCA";
+        const OTHER: &str = "TCTCAAGG";
+        let oriented = options(AdapterConfig { min_score: 20, orient_reads: true, ..adapter_config() });
+        let quality = |len: usize| -> String { (0..len).map(|i| (b'!' + 5 + i as u8) as char).collect() };
+
+        let read = format!("{INSERT}{}{OTHER}", reverse_complement(ADAPTER));
+        let read_quality = quality(read.len());
+        let (parts, tags) = split(&read, &read_quality, &oriented);
+        // Split at the adapter's start in the flipped read.
+        assert_eq!(sequences(&parts), [reverse_complement(OTHER), format!("{ADAPTER}{}", reverse_complement(INSERT))]);
+        let flipped: String = read_quality.chars().rev().collect();
+        assert_eq!(parts[0].2, flipped[..OTHER.len()]);
+        assert_eq!(parts[1].2, flipped[OTHER.len()..]);
+        assert_eq!(tags, ["strand=-"]);
+
+        let read = format!("{OTHER}{ADAPTER}{INSERT}");
+        let read_quality = quality(read.len());
+        let (parts, tags) = split(&read, &read_quality, &oriented);
+        assert_eq!(sequences(&parts), [OTHER.to_string(), format!("{ADAPTER}{INSERT}")]);
+        assert_eq!(parts[1].2, read_quality[OTHER.len()..]);
+        assert_eq!(tags, ["strand=+"]);
+
+        // Without --orient-reads the reverse complement hit stays as read.
+        let read = format!("{INSERT}{}{OTHER}", reverse_complement(ADAPTER));
+        let (parts, tags) = split(&read, &quality(read.len()), &options(AdapterConfig { min_score: 20, ..adapter_config() }));
+        assert_eq!(sequences(&parts), [INSERT.to_string(), format!("{}{OTHER}", reverse_complement(ADAPTER))]);
+        assert!(tags.is_empty());
+    }
+
+    #[test]
+    fn rescued_cdna_reads_are_split_into_parts() {
+        const SSP: &str = "TTTCTGTTGGTGCTGATATTGCTGGG";
+        const VNP: &str = "ACTTGCCTGTCGCTCTATCTTC";
+        const FIRST: &str = "GATTACAGATTACACCATGGTTAACCGGTTAACCATGGATCC";
+        const SECOND: &str = "CCGTAGGCATAGCCGATTCGGACTAAGCTTAGGCCTAGGATC";
+        let mut options = options(adapter_config());
+        options.adapters.clear();
+        options.cdna = Some(cdna::CdnaConfig {
+            ssp: SSP.to_string(),
+            vnp: VNP.to_string(),
+            alignment: AdapterConfig { max_error_rate: Some(0.1), ..adapter_config() },
+        });
+        let plus = |transcript: &str| format!("{SSP}{transcript}{}", reverse_complement(VNP));
+
+        let read = format!("{}{}", plus(FIRST), reverse_complement(&plus(SECOND)));
+        let chunk = ["@r".to_string(), read.clone(), "+".to_string(), "I".repeat(read.len())];
+        let mut batch = BatchResult::new(&options);
+        process_record(&chunk, &options, &mut batch);
+        let records: Vec<(&str, &str)> = batch.lines[0].chunks(4).map(|record| (record[0].as_str(), record[1].as_str())).collect();
+        assert_eq!(records, [("@r_part1", FIRST), ("@r_part2", SECOND)]);
+        assert_eq!((batch.rescued_reads, batch.full_length_reads), (1, 0));
+
+        let read = reverse_complement(&plus(FIRST));
+        let chunk = ["@r".to_string(), read.clone(), "+".to_string(), "I".repeat(read.len())];
+        let mut batch = BatchResult::new(&options);
+        process_record(&chunk, &options, &mut batch);
+        assert_eq!((batch.lines[0][0].as_str(), batch.lines[0][1].as_str()), ("@r", FIRST));
+        assert_eq!(batch.full_length_reads, 1);
+    }
+
+    /// Runs one record through `process_record` and returns its output
+    /// records as (header, sequence) pairs.
+    fn run(read: &str, options: &FilterOptions) -> (BatchResult, Vec<(String, String)>) {
+        let chunk = ["@r".to_string(), read.to_string(), "+".to_string(), "I".repeat(read.len())];
+        let mut batch = BatchResult::new(options);
+        process_record(&chunk, options, &mut batch);
+        let records = batch.lines[0].chunks(4).map(|record| (record[0].clone(), record[1].clone())).collect();
+        (batch, records)
+    }
+
+    fn rna_options(trim_tail: bool, min_tail_length: usize) -> FilterOptions<'static> {
+        let mut options = options(AdapterConfig { min_score: 20, ..adapter_config() });
+        options.rna = Some(RnaConfig { tail_error_rate: 0.1, trim_tail, min_tail_length });
+        options
+    }
+
+    #[test]
+    fn polya_tail_ends_at_a_3_prime_adapter() {
+        const BODY: &str = "GUCAGUUCGAUCGGUACUUGCGUCAGUUCGAUCGGUACUUGC";
+        let read = format!("{BODY}{}{ADAPTER}GGCC", "A".repeat(20));
+        let (_, records) = run(&read, &rna_options(false, 0));
+        assert_eq!(records[0].0, "@r_part1 pt:i:20");
+        assert_eq!(records[0].1, format!("{BODY}{}", "A".repeat(20)));
+        assert_eq!(records[1].0, "@r_part2 pt:i:20");
+
+        // Without an adapter the tail runs to the read end.
+        let read = format!("{BODY}{}", "A".repeat(15));
+        let (_, records) = run(&read, &rna_options(false, 0));
+        assert_eq!(records, [("@r pt:i:15".to_string(), read.clone())]);
+
+        // An adapter in the 5' half is not where the tail stops.
+        let read = format!("{ADAPTER}{BODY}{}", "A".repeat(12));
+        let (_, records) = run(&read, &rna_options(false, 0));
+        assert!(records.iter().all(|(header, _)| header.ends_with(" pt:i:12")), "{:?}", records);
+    }
+
+    #[test]
+    fn polya_tail_trimming_and_minimum() {
+        const BODY: &str = "GUCAGUUCGAUCGGUACUUGCGUCAGUUCGAUCGGUACUUGC";
+        // Trimming drops the tail and the adapter after it.
+        let read = format!("{BODY}{}{ADAPTER}GGCC", "A".repeat(20));
+        let (_, records) = run(&read, &rna_options(true, 0));
+        assert_eq!(records, [("@r pt:i:20".to_string(), BODY.to_string())]);
+
+        let read = format!("{BODY}{}", "A".repeat(15));
+        let (batch, records) = run(&read, &rna_options(false, 16));
+        assert!(records.is_empty());
+        assert_eq!((batch.short_tail_reads, batch.filtered_reads, batch.outputs[0].filtered), (1, 1, 1));
+        let (batch, records) = run(&read, &rna_options(false, 15));
+        assert_eq!((batch.short_tail_reads, records.len()), (0, 1));
+    }
+
+    #[test]
+    fn base_composition_counts() {
+        let composition = BaseComposition::of("GGCCAATTNNNRYacguNNN");
+        assert_eq!((composition.length, composition.gc, composition.called, composition.n), (20, 6, 12, 6));
+        // R, Y and the Ns are left out of the GC denominator.
+        assert!((composition.gc_fraction() - 0.5).abs() < 1e-9);
+        assert!((composition.n_fraction() - 0.3).abs() < 1e-9);
+        assert_eq!(composition.longest_n_run, 3);
+        assert_eq!(BaseComposition::of("NNNN").gc_fraction(), 0.0);
+        assert_eq!(BaseComposition::of("").n_fraction(), 0.0);
+        assert_eq!(BaseComposition::of("ANNAnnnnA").longest_n_run, 4);
+    }
+
+    #[test]
+    fn n_limits_apply_before_gc() {
+        let mut options = options(adapter_config());
+        options.adapters.clear();
+        options.composition = CompositionFilter { min_gc: Some(0.4), max_gc: Some(0.6), max_n_fraction: Some(0.2), max_n_run: None };
+
+        // Fails both limits and counts as an N failure only.
+        let (batch, _) = run(&format!("AAAAAAAAAA{}", "N".repeat(5)), &options);
+        assert_eq!((batch.n_filtered_reads, batch.gc_filtered_reads), (1, 0));
+        assert_eq!((batch.outputs[0].n_filtered, batch.outputs[0].gc_filtered, batch.filtered_reads), (1, 0, 1));
+
+        let (batch, _) = run("AAAAAAAAAAGCN", &options);
+        assert_eq!((batch.n_filtered_reads, batch.gc_filtered_reads), (0, 1));
+
+        // GC of the called bases is 0.5 however many Ns there are.
+        let (batch, records) = run("AAGGCCTTNN", &options);
+        assert_eq!((batch.n_filtered_reads, batch.gc_filtered_reads, records.len()), (0, 0, 1));
+        assert_eq!((batch.outputs[0].called_bases, batch.outputs[0].gc_bases, batch.outputs[0].n_bases), (8, 4, 2));
+    }
+
+    #[test]
+    fn dedup_only_changes_the_counts_of_duplicates() {
+        let reads = [
+            format!("GATTACAGATTACACCATGG{ADAPTER}TTAACCGGTTAACCATGGATCC"),
+            "CCGTAGGCATAGCCGATTCGG".to_string(),
+            // The first part is too short and filtered.
+            format!("ACGT{ADAPTER}GGATCCATGCATGCAAGCTTG"),
+            "AAGC".to_string(),
+        ];
+        let lines = |reads: &[String]| -> Vec<String> {
+            reads
+                .iter()
+                .enumerate()
+                .flat_map(|(i, read)| [format!("@r{}", i), read.clone(), "+".to_string(), "I".repeat(read.len())])
+                .collect()
+        };
+        let summary = |batch: &BatchResult| {
+            let stats = &batch.outputs[0];
+            (batch.total_reads, batch.filtered_reads, stats.reads, stats.written, stats.filtered, batch.lines[0].len() / 4)
+        };
+        let mut options = options(AdapterConfig { min_score: 20, ..adapter_config() });
+        options.min_length = 10;
+        let plain = process_batch(&lines(&reads), &options, None);
+        assert_eq!(summary(&plain), (4, 2, 4, 4, 2, 4));
+
+        let dedup = dedup::DedupConfig { mode: dedup::DedupMode::Exact, memory: None, similarity: 0.0 };
+        let deduplicated = process_batch(&lines(&reads), &options, Some(&mut dedup::Deduplicator::new(&dedup)));
+        assert_eq!(summary(&deduplicated), summary(&plain));
+        assert_eq!(deduplicated.duplicate_reads, 0);
+        assert_eq!(deduplicated.lines, plain.lines);
+
+        // A repeat of the split read: both its parts are filtered instead of written.
+        let repeated: Vec<String> = reads.iter().cloned().chain([reads[0].clone()]).collect();
+        let deduplicated = process_batch(&lines(&repeated), &options, Some(&mut dedup::Deduplicator::new(&dedup)));
+        assert_eq!(summary(&deduplicated), (5, 4, 5, 4, 4, 4));
+        assert_eq!(deduplicated.duplicate_reads, 1);
+    }
+}
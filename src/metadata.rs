@@ -0,0 +1,202 @@
+//! Per-read metadata from another tool, loaded with `--metadata` from a
+//! tab-separated table keyed by read ID. Columns can be required with
+//! `--require`, used in `--filter-expr` and copied into headers.
+
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::path::Path;
+
+use crate::expr::{self, CompareOp, Value};
+use crate::ids::{lineage, read_id};
+use crate::open_input;
+
+pub struct MetadataTable {
+    pub columns: Vec<String>,
+    rows: HashMap<Box<str>, Box<[Box<str>]>>,
+}
+
+/// A `--require` condition such as `taxid=9606` or `identity>=0.95`.
+pub struct Requirement {
+    pub column: usize,
+    op: CompareOp,
+    value: Value,
+}
+
+impl MetadataTable {
+    /// Reads a table whose first line names the columns (a leading `#` is
+    /// ignored). Rows are keyed by `id_column`; the first row of an ID wins.
+    pub fn load(path: &Path, id_column: &str) -> Result<MetadataTable, String> {
+        Self::read(open_input(path).map_err(|e| e.to_string())?, id_column)
+    }
+
+    fn read(reader: impl BufRead, id_column: &str) -> Result<MetadataTable, String> {
+        let mut lines = reader.lines();
+        let header = lines.next().ok_or("the table is empty")?.map_err(|e| e.to_string())?;
+        let columns: Vec<String> = header.trim_start_matches('#').split('\t').map(|column| column.trim().to_string()).collect();
+        let id_index = columns
+            .iter()
+            .position(|column| column == id_column)
+            .ok_or_else(|| format!("no '{}' column in the header", id_column))?;
+
+        let mut rows = HashMap::new();
+        for (number, line) in lines.enumerate() {
+            let line = line.map_err(|e| e.to_string())?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let fields: Box<[Box<str>]> = line.split('\t').map(|field| field.trim().into()).collect();
+            if fields.len() != columns.len() {
+                return Err(format!("line {}: expected {} columns, found {}", number + 2, columns.len(), fields.len()));
+            }
+            let id: Box<str> = fields[id_index].trim_start_matches('@').into();
+            rows.entry(id).or_insert(fields);
+        }
+        Ok(MetadataTable { columns, rows })
+    }
+
+    pub fn column(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|column| column == name)
+    }
+
+    /// Row of a read, falling back to the read a `_partN` record came from.
+    pub fn row(&self, header: &str) -> Option<&[Box<str>]> {
+        lineage(read_id(header)).find_map(|id| self.rows.get(id)).map(|row| &row[..])
+    }
+
+    /// Parses `column<op>value` with `=`, `==`, `!=`, `<`, `<=`, `>` or `>=`.
+    /// Values that parse as numbers compare numerically.
+    pub fn requirement(&self, spec: &str) -> Result<Requirement, String> {
+        let split = spec.find(['=', '!', '<', '>']).ok_or_else(|| format!("'{}' has no comparison", spec))?;
+        let (name, rest) = spec.split_at(split);
+        let column = self.column(name.trim()).ok_or_else(|| format!("no '{}' column in the metadata table", name.trim()))?;
+        // Longer operators first so that `<=` isn't read as `<`.
+        let (op, length) = [
+            ("==", CompareOp::Eq),
+            ("!=", CompareOp::Ne),
+            ("<=", CompareOp::Le),
+            (">=", CompareOp::Ge),
+            ("=", CompareOp::Eq),
+            ("<", CompareOp::Lt),
+            (">", CompareOp::Gt),
+        ]
+        .into_iter()
+        .find(|(symbol, _)| rest.starts_with(symbol))
+        .map(|(symbol, op)| (op, symbol.len()))
+        .ok_or_else(|| format!("'{}' has no comparison", spec))?;
+        let value = rest[length..].trim();
+        let value = match value.parse() {
+            Ok(number) => Value::Number(number),
+            Err(_) => Value::Text(value.to_string()),
+        };
+        Ok(Requirement { column, op, value })
+    }
+}
+
+impl Requirement {
+    pub fn holds(&self, row: &[Box<str>]) -> bool {
+        let field = Value::Text(row[self.column].to_string());
+        expr::compare(&field, &self.value).is_some_and(|ordering| self.op.holds(ordering))
+    }
+}
+
+pub struct MetadataConfig {
+    pub table: MetadataTable,
+    /// Reads must have a row meeting all of these.
+    pub requirements: Vec<Requirement>,
+    /// Columns copied into the header as `column=value`.
+    pub tag_columns: Vec<usize>,
+}
+
+impl MetadataConfig {
+    /// Header tags of the tag columns for a row. Whitespace and `%` are
+    /// percent-encoded so each tag stays one header word, e.g.
+    /// `name=Homo%20sapiens`.
+    pub fn tags<'a>(&'a self, row: &'a [Box<str>]) -> impl Iterator<Item = String> + 'a {
+        self.tag_columns
+            .iter()
+            .map(move |&column| format!("{}={}", escape(&self.table.columns[column]), escape(&row[column])))
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c == '%' || c.is_whitespace() {
+            let mut buf = [0; 4];
+            for byte in c.encode_utf8(&mut buf).bytes() {
+                escaped.push_str(&format!("%{:02X}", byte));
+            }
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABLE: &str = "#read_id\ttaxid\tname\tidentity\n\
+        @r1\t9606\tHomo sapiens\t0.97\n\
+        \n\
+        r2\t10\tbeta\t0.9\n\
+        r1\t562\tEscherichia coli\t0.99\n";
+
+    fn table() -> MetadataTable {
+        MetadataTable::read(TABLE.as_bytes(), "read_id").unwrap()
+    }
+
+    #[test]
+    fn load_keys_rows_by_id() {
+        let table = table();
+        assert_eq!(table.columns, ["read_id", "taxid", "name", "identity"]);
+        // The leading @ is dropped and the first row of r1 wins.
+        assert_eq!(&*table.row("@r1 ch=7").unwrap()[1], "9606");
+        assert_eq!(&*table.row("@r2").unwrap()[2], "beta");
+        assert!(table.row("@r3").is_none());
+    }
+
+    #[test]
+    fn load_errors() {
+        let err = MetadataTable::read("id\ta\nr1\t1\nr2\t2\t3\n".as_bytes(), "id").err().unwrap();
+        assert_eq!(err, "line 3: expected 2 columns, found 3");
+        assert!(MetadataTable::read("id\ta\n".as_bytes(), "read_id").is_err());
+        assert!(MetadataTable::read("".as_bytes(), "id").is_err());
+    }
+
+    #[test]
+    fn parts_fall_back_to_their_read() {
+        let table = table();
+        assert_eq!(&*table.row("@r2_part2").unwrap()[1], "10");
+        assert_eq!(&*table.row("@r1_part1_part3 pt:i:20").unwrap()[1], "9606");
+    }
+
+    #[test]
+    fn requirements_compare_numbers_and_text() {
+        let table = table();
+        let holds = |spec: &str, id: &str| table.requirement(spec).unwrap().holds(table.row(id).unwrap());
+        assert!(holds("taxid=9606", "@r1") && holds("taxid==9606", "@r1") && !holds("taxid=9606", "@r2"));
+        assert!(holds("taxid!=9606", "@r2") && !holds("taxid!=9606", "@r1"));
+        // 10 < 9606 as numbers, though not as text.
+        assert!(holds("taxid<=9606", "@r2") && holds("taxid < 100", "@r2"));
+        assert!(holds("identity>=0.95", "@r1") && !holds("identity>=0.95", "@r2") && holds("identity>0.9", "@r1"));
+        assert!(holds("name=Homo sapiens", "@r1") && holds("name>=beta", "@r2") && !holds("name<beta", "@r2"));
+        // A text field never passes a numeric comparison.
+        assert!(!holds("name<=5", "@r1") && !holds("name!=5", "@r1"));
+
+        assert!(table.requirement("taxid").is_err());
+        assert!(table.requirement("genus=Homo").is_err());
+        assert_eq!(table.requirement("identity >= 0.95").unwrap().column, 3);
+    }
+
+    #[test]
+    fn tags_stay_one_word() {
+        let table = MetadataTable { columns: vec!["read_id".to_string(), "species name".to_string()], rows: HashMap::new() };
+        let config = MetadataConfig { table, requirements: Vec::new(), tag_columns: vec![1] };
+        let row: Box<[Box<str>]> = Box::new(["r1".into(), "Homo sapiens\t100%".into()]);
+        assert_eq!(config.tags(&row).collect::<Vec<_>>(), ["species%20name=Homo%20sapiens%09100%25"]);
+        assert_eq!(escape("9606"), "9606");
+        assert_eq!(escape("a\u{a0}b"), "a%C2%A0b");
+    }
+}
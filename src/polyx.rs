@@ -0,0 +1,121 @@
+//! Homopolymer runs at read ends, such as poly(A) tails.
+
+use std::cmp::Reverse;
+
+/// `read_base` is `base` itself, in either case; RNA `U` counts as `T`.
+/// Ambiguity codes such as `N` are other bases, so they never extend a run
+/// for free.
+fn is_base(read_base: u8, base: u8) -> bool {
+    match read_base.to_ascii_uppercase() {
+        b'U' => base == b'T',
+        read_base => read_base == base,
+    }
+}
+
+/// Length of the run of `base` at the start of `sequence` (or at its end
+/// with `from_end`), allowing up to `max_error_rate` other bases, `N`
+/// included. The run always ends on a `base`, and it stops once errors
+/// exceed that rate by more than two bases.
+pub fn end_run_length(sequence: &[u8], base: u8, max_error_rate: f64, from_end: bool) -> usize {
+    let mut errors = 0;
+    let mut run = 0;
+    for length in 1..=sequence.len() {
+        let read_base = if from_end { sequence[sequence.len() - length] } else { sequence[length - 1] };
+        let allowed = max_error_rate * length as f64;
+        if is_base(read_base, base) {
+            if errors as f64 <= allowed {
+                run = length;
+            }
+        } else {
+            errors += 1;
+            if errors as f64 > allowed + 2.0 {
+                break;
+            }
+        }
+    }
+    run
+}
+
+/// Bases that can be trimmed as homopolymers, in the order of the
+/// per-base trimmed counts.
+pub const POLYX_BASES: &[u8; 4] = b"ACGT";
+
+/// Range of `sequence` left after trimming runs of at least `min_length`
+/// of any of `bases` from both ends. The longest run is trimmed first (the
+/// earliest of A, C, G and T on ties), and trimming repeats so that e.g. a
+/// poly(A) behind a poly(G) goes too. Ns belong to no run.
+/// Trimmed bases are added to `trimmed`, indexed like [`POLYX_BASES`].
+pub fn trim_runs(
+    sequence: &[u8],
+    bases: &[u8],
+    min_length: usize,
+    max_error_rate: f64,
+    trimmed: &mut [usize; 4],
+) -> (usize, usize) {
+    let mut start = 0;
+    let mut end = sequence.len();
+    for from_end in [false, true] {
+        loop {
+            let longest = bases
+                .iter()
+                .map(|&base| (end_run_length(&sequence[start..end], base, max_error_rate, from_end), base))
+                .max_by_key(|&(run, base)| (run, Reverse(base)));
+            let Some((run, base)) = longest.filter(|&(run, _)| run > 0 && run >= min_length) else { break };
+            if from_end {
+                end -= run;
+            } else {
+                start += run;
+            }
+            if let Some(index) = POLYX_BASES.iter().position(|&b| b == base) {
+                trimmed[index] += run;
+            }
+        }
+    }
+    (start, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_allows_errors_at_the_rate() {
+        assert_eq!(end_run_length(b"CGTAAAAAAAAAA", b'A', 0.0, true), 10);
+        assert_eq!(end_run_length(b"CGTAAAAAGAAAAAAAAAA", b'A', 0.1, true), 16);
+        assert_eq!(end_run_length(b"aaaaaCG", b'A', 0.0, false), 5);
+        assert_eq!(end_run_length(b"GUUUU", b'T', 0.0, true), 4);
+    }
+
+    #[test]
+    fn n_is_not_part_of_a_run() {
+        assert_eq!(end_run_length(b"NNNNNNNN", b'A', 0.1, true), 0);
+        assert_eq!(end_run_length(b"CGNNNNNNAAAA", b'A', 0.0, true), 4);
+        // An N inside a long tail is just one error.
+        assert_eq!(end_run_length(b"CGTAAAAANAAAAAAAAAA", b'A', 0.1, true), 16);
+    }
+
+    #[test]
+    fn longest_runs_are_trimmed_from_both_ends() {
+        let mut trimmed = [0; 4];
+        let read = b"TTTTTTTTACGTACGTACGTGGGGGGAAAAAAAAAA";
+        assert_eq!(trim_runs(read, POLYX_BASES, 5, 0.0, &mut trimmed), (8, 20));
+        assert_eq!(trimmed, [10, 0, 6, 8]);
+    }
+
+    #[test]
+    fn n_runs_are_not_trimmed() {
+        let mut trimmed = [0; 4];
+        let read = b"NNNNNNNNACGTACGTACGTNNNNNNNNNN";
+        assert_eq!(trim_runs(read, POLYX_BASES, 5, 0.1, &mut trimmed), (0, read.len()));
+        assert_eq!(trimmed, [0; 4]);
+    }
+
+    #[test]
+    fn n_tail_is_not_counted_as_poly_t() {
+        let mut trimmed = [0; 4];
+        // The Ns go with the poly(A) as its errors.
+        let read = b"ACGTACGTACGTAAAAAAAAAANN";
+        assert_eq!(trim_runs(read, POLYX_BASES, 5, 0.2, &mut trimmed), (0, 12));
+        assert_eq!(trimmed, [12, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,222 @@
+//! Per-read quality metrics computed from the Phred+33 quality string, as
+//! an alternative to the score in the read header.
+
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QualityMetric {
+    /// The score at the end of the read header.
+    Header,
+    /// Phred score of the mean error probability.
+    Mean,
+    Median,
+    /// Lowest mean quality of any window of this many bases.
+    MinWindow(usize),
+    /// Sum of the error probabilities; lower is better.
+    ExpectedErrors,
+}
+
+impl FromStr for QualityMetric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "header" => Ok(QualityMetric::Header),
+            "mean" => Ok(QualityMetric::Mean),
+            "median" => Ok(QualityMetric::Median),
+            "expected-errors" => Ok(QualityMetric::ExpectedErrors),
+            _ => match s.strip_prefix("min-window:").map(str::parse) {
+                Some(Ok(window)) if window > 0 => Ok(QualityMetric::MinWindow(window)),
+                _ => Err(format!("unknown quality metric '{}'", s)),
+            },
+        }
+    }
+}
+
+fn error_probability(qual: u8) -> f64 {
+    10f64.powf(-(qual.saturating_sub(33) as f64) / 10.0)
+}
+
+fn phred(error_probability: f64) -> f64 {
+    -10.0 * error_probability.max(1e-10).log10()
+}
+
+pub fn expected_errors(quality: &str) -> f64 {
+    quality.bytes().map(error_probability).sum()
+}
+
+pub fn mean_quality(quality: &str) -> f64 {
+    if quality.is_empty() {
+        return 0.0;
+    }
+    phred(expected_errors(quality) / quality.len() as f64)
+}
+
+pub fn median_quality(quality: &str) -> f64 {
+    let mut scores: Vec<u8> = quality.bytes().map(|q| q.saturating_sub(33)).collect();
+    if scores.is_empty() {
+        return 0.0;
+    }
+    scores.sort_unstable();
+    let mid = scores.len() / 2;
+    if scores.len().is_multiple_of(2) {
+        (scores[mid - 1] as f64 + scores[mid] as f64) / 2.0
+    } else {
+        scores[mid] as f64
+    }
+}
+
+/// Lowest mean quality over a sliding window; reads shorter than the
+/// window are one window.
+pub fn min_window_quality(quality: &str, window: usize) -> f64 {
+    let qual = quality.as_bytes();
+    if qual.len() <= window {
+        return mean_quality(quality);
+    }
+    let mut errors: f64 = qual[..window].iter().map(|&q| error_probability(q)).sum();
+    let mut worst = errors;
+    for i in window..qual.len() {
+        errors += error_probability(qual[i]) - error_probability(qual[i - window]);
+        worst = worst.max(errors);
+    }
+    phred(worst / window as f64)
+}
+
+impl QualityMetric {
+    /// The metric for a read, or `None` for [`QualityMetric::Header`].
+    pub fn compute(&self, quality: &str) -> Option<f64> {
+        match *self {
+            QualityMetric::Header => None,
+            QualityMetric::Mean => Some(mean_quality(quality)),
+            QualityMetric::Median => Some(median_quality(quality)),
+            QualityMetric::MinWindow(window) => Some(min_window_quality(quality, window)),
+            QualityMetric::ExpectedErrors => Some(expected_errors(quality)),
+        }
+    }
+
+    /// The metric on the Phred scale, as header scores are: the mean
+    /// quality for the header and expected-errors metrics.
+    pub fn phred_quality(&self, quality: &str) -> f64 {
+        match *self {
+            QualityMetric::Header | QualityMetric::ExpectedErrors => mean_quality(quality),
+            _ => self.compute(quality).unwrap(),
+        }
+    }
+
+    pub fn label(&self) -> String {
+        match self {
+            QualityMetric::Header => "quality".to_string(),
+            QualityMetric::Mean => "mean quality".to_string(),
+            QualityMetric::Median => "median quality".to_string(),
+            QualityMetric::MinWindow(window) => format!("min {}-base window quality", window),
+            QualityMetric::ExpectedErrors => "expected errors".to_string(),
+        }
+    }
+}
+
+/// Minimum quality as a step function of read length, parsed from
+/// `length:quality` pairs such as `0:10,5000:8,50000:6`.
+#[derive(Clone, Debug)]
+pub struct LengthThresholds {
+    /// `(minimum length, minimum quality)`, sorted by length.
+    pub steps: Vec<(usize, f64)>,
+}
+
+impl FromStr for LengthThresholds {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut steps = Vec::new();
+        for step in s.split(',') {
+            let parsed = step
+                .split_once(':')
+                .and_then(|(length, quality)| Some((length.trim().parse().ok()?, quality.trim().parse().ok()?)));
+            match parsed {
+                Some(step) => steps.push(step),
+                None => return Err(format!("'{}' is not a length:quality pair", step)),
+            }
+        }
+        steps.sort_by_key(|&(length, _)| length);
+        if steps.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+            return Err("a length appears twice".to_string());
+        }
+        Ok(LengthThresholds { steps })
+    }
+}
+
+impl LengthThresholds {
+    /// Makes sure the curve starts at length 0, using `default` below its
+    /// first length.
+    pub fn with_default(mut self, default: f64) -> Self {
+        if self.steps.first().is_none_or(|&(length, _)| length > 0) {
+            self.steps.insert(0, (0, default));
+        }
+        self
+    }
+
+    /// Index of the step that applies to a read of `length` bases.
+    pub fn step_for(&self, length: usize) -> usize {
+        self.steps.partition_point(|&(start, _)| start <= length).saturating_sub(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_parse() {
+        assert_eq!("mean".parse(), Ok(QualityMetric::Mean));
+        assert_eq!("min-window:50".parse(), Ok(QualityMetric::MinWindow(50)));
+        assert!("min-window:0".parse::<QualityMetric>().is_err());
+        assert!("min-window:x".parse::<QualityMetric>().is_err());
+        assert!("max".parse::<QualityMetric>().is_err());
+    }
+
+    #[test]
+    fn metric_values() {
+        // Q10 and Q30: error probabilities 0.1 and 0.001.
+        let quality = "++++????";
+        assert!((expected_errors(quality) - 0.404).abs() < 1e-9);
+        assert!((mean_quality(quality) - 12.97).abs() < 0.01);
+        assert_eq!(median_quality(quality), 20.0);
+        assert_eq!(median_quality("+??"), 30.0);
+        assert!((min_window_quality(quality, 4) - 10.0).abs() < 1e-9);
+        assert!((min_window_quality("????++", 2) - 10.0).abs() < 1e-9);
+        assert_eq!(mean_quality(""), 0.0);
+    }
+
+    #[test]
+    fn phred_quality_stays_on_the_phred_scale() {
+        let quality = "++++????";
+        assert_eq!(QualityMetric::ExpectedErrors.compute(quality), Some(expected_errors(quality)));
+        assert_eq!(QualityMetric::ExpectedErrors.phred_quality(quality), mean_quality(quality));
+        assert_eq!(QualityMetric::Header.compute(quality), None);
+        assert_eq!(QualityMetric::Header.phred_quality(quality), mean_quality(quality));
+        assert_eq!(QualityMetric::Median.phred_quality(quality), 20.0);
+    }
+
+    #[test]
+    fn thresholds_parse_sorted() {
+        let thresholds: LengthThresholds = "5000:8, 0:10,50000:6".parse().unwrap();
+        assert_eq!(thresholds.steps, [(0, 10.0), (5000, 8.0), (50000, 6.0)]);
+        assert!("0:10,0:8".parse::<LengthThresholds>().is_err());
+        assert!("0:10,5000".parse::<LengthThresholds>().is_err());
+        assert!("x:10".parse::<LengthThresholds>().is_err());
+    }
+
+    #[test]
+    fn thresholds_start_at_zero() {
+        let thresholds = "1000:9".parse::<LengthThresholds>().unwrap().with_default(7.0);
+        assert_eq!(thresholds.steps, [(0, 7.0), (1000, 9.0)]);
+        let thresholds = "0:10".parse::<LengthThresholds>().unwrap().with_default(7.0);
+        assert_eq!(thresholds.steps, [(0, 10.0)]);
+    }
+
+    #[test]
+    fn step_applies_from_its_length() {
+        let thresholds: LengthThresholds = "0:10,5000:8,50000:6".parse().unwrap();
+        let steps: Vec<usize> = [0, 4999, 5000, 49999, 50000, 1_000_000].iter().map(|&length| thresholds.step_for(length)).collect();
+        assert_eq!(steps, [0, 0, 1, 1, 2, 2]);
+    }
+}
@@ -0,0 +1,166 @@
+//! UMI extraction. A pattern such as `CAGCACCTNNNNNNNNNNNNGTTGTACTTCG` gives
+//! the bases on either side of the UMI (here an adapter flank and a primer);
+//! the UMI is read off between them, next to an adapter hit.
+
+use crate::align::{smith_waterman_align, Scoring};
+use crate::reverse_complement;
+
+#[derive(Clone, Debug)]
+pub struct UmiPattern {
+    /// Bases between the adapter and the UMI; may be empty.
+    pub flank: String,
+    pub length: usize,
+    /// Bases between the UMI and the insert; may be empty.
+    pub primer: String,
+    /// Maximum errors per base for the flank and primer, and for the UMI length.
+    pub max_error_rate: f64,
+}
+
+impl UmiPattern {
+    /// Parses a pattern with a single run of `N`s marking the UMI.
+    pub fn parse(pattern: &str, max_error_rate: f64) -> Result<UmiPattern, String> {
+        let pattern = pattern.to_ascii_uppercase();
+        let start = pattern.find('N').ok_or("the pattern has no run of N bases marking the UMI")?;
+        let end = pattern[start..].find(|c| c != 'N').map_or(pattern.len(), |offset| start + offset);
+        if pattern[end..].contains('N') {
+            return Err("the pattern has more than one run of N bases".to_string());
+        }
+        Ok(UmiPattern {
+            flank: pattern[..start].to_string(),
+            length: end - start,
+            primer: pattern[end..].to_string(),
+            max_error_rate,
+        })
+    }
+
+    fn pattern_len(&self) -> usize {
+        self.flank.len() + self.length + self.primer.len()
+    }
+
+    fn budget(&self, length: usize) -> usize {
+        (self.max_error_rate * length as f64).floor() as usize
+    }
+
+    /// Read range of `part` in `region[from..]`, if it aligns across all but
+    /// its error budget.
+    fn find_part(&self, region: &str, from: usize, part: &str, scoring: &Scoring) -> Option<(usize, usize)> {
+        let hit = smith_waterman_align(&region[from..], "", part, scoring)?;
+        let budget = self.budget(part.len());
+        if hit.aligned_length() + budget < part.len() || hit.mismatches + hit.indels > budget {
+            return None;
+        }
+        Some((from + hit.read_start, from + hit.read_end))
+    }
+
+    /// UMI range in a region that starts right after the adapter.
+    fn locate(&self, region: &str, scoring: &Scoring) -> Option<(usize, usize)> {
+        let umi_start = if self.flank.is_empty() {
+            0
+        } else {
+            self.find_part(region, 0, &self.flank, scoring)?.1
+        };
+        let umi_end = if self.primer.is_empty() {
+            (umi_start + self.length).min(region.len())
+        } else {
+            self.find_part(region, umi_start, &self.primer, scoring)?.0
+        };
+        let tolerance = self.budget(self.length).max(1);
+        if umi_end < umi_start || umi_end - umi_start + tolerance < self.length || umi_end - umi_start > self.length + tolerance {
+            return None;
+        }
+        Some((umi_start, umi_end))
+    }
+
+    /// Finds the UMI next to an adapter hit: downstream of `anchor` for a
+    /// forward adapter, upstream of it (and reverse complemented) for a
+    /// reverse-complement one. Returns the read range and the UMI as it
+    /// reads in the pattern's orientation.
+    pub fn extract(&self, sequence: &str, anchor: usize, upstream: bool, scoring: &Scoring) -> Option<(usize, usize, String)> {
+        // Room for the adapter hit stopping a few bases short of its end.
+        let span = self.pattern_len() + self.pattern_len() / 2;
+        if upstream {
+            let start = anchor.saturating_sub(span);
+            let region = reverse_complement(&sequence[start..anchor]);
+            let (umi_start, umi_end) = self.locate(&region, scoring)?;
+            Some((anchor - umi_end, anchor - umi_start, region[umi_start..umi_end].to_string()))
+        } else {
+            let end = (anchor + span).min(sequence.len());
+            let (umi_start, umi_end) = self.locate(&sequence[anchor..end], scoring)?;
+            Some((anchor + umi_start, anchor + umi_end, sequence[anchor + umi_start..anchor + umi_end].to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FLANK: &str = "CAGCACCT";
+    const PRIMER: &str = "GTTGTACTTCG";
+    /// Not its own reverse complement, so a flipped UMI shows up.
+    const UMI: &str = "GATTACCAGTCC";
+    const INSERT: &str = "TCTCAAGGCTAGCGATCCGAAT";
+
+    fn pattern(text: &str) -> UmiPattern {
+        UmiPattern::parse(text, 0.1).unwrap()
+    }
+
+    fn full() -> UmiPattern {
+        pattern(&format!("{FLANK}{}{PRIMER}", "N".repeat(12)))
+    }
+
+    #[test]
+    fn parse_splits_on_the_n_run() {
+        let parsed = pattern("cagcacctNNNNnnnnNNNNgttgtacttcg");
+        assert_eq!((parsed.flank.as_str(), parsed.length, parsed.primer.as_str()), (FLANK, 12, PRIMER));
+        let flank_only = pattern("CAGCACCTNNNNNN");
+        assert_eq!((flank_only.flank.as_str(), flank_only.length, flank_only.primer.as_str()), (FLANK, 6, ""));
+        let primer_only = pattern("NNNNNNGTTGTACTTCG");
+        assert_eq!((primer_only.flank.as_str(), primer_only.length, primer_only.primer.as_str()), ("", 6, PRIMER));
+        assert!(UmiPattern::parse("CAGCACCTNNNNGTTNNNNG", 0.1).is_err());
+        assert!(UmiPattern::parse("CAGCACCTGTTGTACTTCG", 0.1).is_err());
+    }
+
+    #[test]
+    fn umi_downstream_of_a_forward_adapter() {
+        let sequence = format!("GGGGGGGGGG{FLANK}{UMI}{PRIMER}{INSERT}");
+        let found = full().extract(&sequence, 10, false, &Scoring::default());
+        assert_eq!(found, Some((18, 30, UMI.to_string())));
+    }
+
+    #[test]
+    fn umi_upstream_of_a_reverse_complement_adapter() {
+        let sequence = format!("{INSERT}{}GGGGGGGGGG", reverse_complement(&format!("{FLANK}{UMI}{PRIMER}")));
+        let anchor = sequence.len() - 10;
+        let (start, end, umi) = full().extract(&sequence, anchor, true, &Scoring::default()).unwrap();
+        // The UMI comes back as it reads in the pattern, not as in the read.
+        assert_eq!(umi, UMI);
+        assert_eq!(&sequence[start..end], reverse_complement(UMI));
+        assert_eq!((start, end), (INSERT.len() + PRIMER.len(), anchor - FLANK.len()));
+    }
+
+    #[test]
+    fn flank_or_primer_alone_anchors_the_umi() {
+        let scoring = Scoring::default();
+        let flank_only = pattern(&format!("{FLANK}{}", "N".repeat(12)));
+        let sequence = format!("GGGGGGGGGG{FLANK}{UMI}{INSERT}");
+        assert_eq!(flank_only.extract(&sequence, 10, false, &scoring), Some((18, 30, UMI.to_string())));
+
+        let primer_only = pattern(&format!("{}{PRIMER}", "N".repeat(12)));
+        let sequence = format!("GGGGGGGGGG{UMI}{PRIMER}{INSERT}");
+        assert_eq!(primer_only.extract(&sequence, 10, false, &scoring), Some((10, 22, UMI.to_string())));
+    }
+
+    #[test]
+    fn umi_length_is_checked_within_the_error_rate() {
+        let scoring = Scoring::default();
+        let with_umi = |umi: &str| format!("GGGGGGGGGG{FLANK}{umi}{PRIMER}{INSERT}");
+        // One base off is within 10% of 12; two are not.
+        let longer = format!("{UMI}A");
+        assert_eq!(full().extract(&with_umi(&longer), 10, false, &scoring).map(|hit| hit.2), Some(longer));
+        let shorter = &UMI[..11];
+        assert_eq!(full().extract(&with_umi(shorter), 10, false, &scoring).map(|hit| hit.2), Some(shorter.to_string()));
+        assert_eq!(full().extract(&with_umi(&format!("{UMI}AA")), 10, false, &scoring), None);
+        assert_eq!(full().extract(&with_umi(&UMI[..10]), 10, false, &scoring), None);
+    }
+}